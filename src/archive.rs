@@ -0,0 +1,453 @@
+use crate::atomic;
+use crate::observer::BackupObserver;
+use crate::plan::{Action, PlannedFile};
+use crate::pool::Step;
+use crate::report::Outcome;
+use crate::repository;
+use crate::settings::Archive;
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike};
+use failure::Error;
+use std::fs::{self, File, FileTimes};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// One file in an archive.
+#[derive(Debug, Serialize, Clone)]
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    pub bytes: u64,
+    /// Why the file could not be extracted, when its data is damaged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Reads exactly the `left` bytes a tar header was written for, failing if
+/// the file was truncated since, and ignoring what was appended.
+struct Exact {
+    file: File,
+    left: u64,
+}
+
+impl io::Read for Exact {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        match self.file.read(&mut buf[..len])? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while it was archived",
+            )),
+            read => {
+                self.left -= read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+enum Writer {
+    TarZst(tar::Builder<zstd::stream::write::Encoder<'static, File>>),
+    Zip(ZipWriter<File>),
+}
+
+/// The name of the entry for `path`, relative to the archive.
+pub(crate) fn entry_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|x| match x {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Zip keeps local times from 1980 to 2107, so other times are clamped.
+fn zip_time(md: &fs::Metadata) -> zip::DateTime {
+    let time: DateTime<Local> = match md.modified() {
+        Ok(time) => time.into(),
+        Err(_) => return zip::DateTime::default(),
+    };
+
+    zip::DateTime::from_date_and_time(
+        time.year().clamp(1980, 2107) as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+impl Writer {
+    fn create(format: Archive, file: File) -> Result<Writer, Error> {
+        match format {
+            Archive::TarZst => Ok(Writer::TarZst(tar::Builder::new(
+                zstd::stream::write::Encoder::new(file, 0)?,
+            ))),
+            Archive::Zip => Ok(Writer::Zip(ZipWriter::new(file))),
+            Archive::Off => Err(format_err!("not an archive format")),
+        }
+    }
+
+    /// Adds `file` as `name`. An error leaves the archive corrupt.
+    fn append(&mut self, mut file: File, name: &str) -> Result<(), Error> {
+        match self {
+            Writer::TarZst(builder) => {
+                let md = file.metadata()?;
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&md);
+                let left = header.size()?;
+                builder.append_data(&mut header, name, Exact { file, left })?;
+            }
+            Writer::Zip(writer) => {
+                let md = file.metadata()?;
+                let mut options = FileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .last_modified_time(zip_time(&md))
+                    .large_file(md.len() >= u32::MAX as u64);
+
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    options = options.unix_permissions(md.permissions().mode());
+                }
+
+                writer.start_file(name, options)?;
+                io::copy(&mut file, writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<File, Error> {
+        match self {
+            Writer::TarZst(builder) => Ok(builder.into_inner()?.finish()?),
+            Writer::Zip(mut writer) => Ok(writer.finish()?),
+        }
+    }
+}
+
+/// Writes the files `Copy` plans for to the archive at `path`, reporting
+/// each to `sink`. A file that can't be opened is reported as failed, but
+/// failing to read one part way aborts the archive, which is not kept.
+pub(crate) fn write<F>(path: &Path, files: &[PlannedFile], mut sink: F) -> Result<(), Error>
+where
+    F: FnMut(&PlannedFile, Step<Outcome>),
+{
+    let format = Archive::of(path);
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    atomic::write(path, |partial| {
+        let mut writer = Writer::create(format, File::create(partial)?)?;
+
+        for file in files {
+            sink(file, Step::Started);
+            let outcome = match (&file.action, &file.dest) {
+                (Action::Copy, Some(dest)) => {
+                    let name = entry_name(dest.strip_prefix(path).unwrap_or(dest));
+                    match File::open(&file.src) {
+                        Ok(src) => {
+                            writer.append(src, &name).map_err(|e| {
+                                format_err!(
+                                    "archive aborted, unable to add {}: {}",
+                                    file.src.to_string_lossy(),
+                                    e
+                                )
+                            })?;
+                            Outcome::Copied
+                        }
+                        Err(e) => Outcome::Failed {
+                            error: e.to_string(),
+                        },
+                    }
+                }
+                (Action::Fail { error }, _) => Outcome::Failed {
+                    error: error.clone(),
+                },
+                _ => Outcome::Failed {
+                    error: "only copies can be archived".to_owned(),
+                },
+            };
+            sink(file, Step::Done(outcome));
+        }
+
+        writer.finish()
+    })
+}
+
+fn tar_reader(path: &Path) -> Result<tar::Archive<impl io::Read>, Error> {
+    Ok(tar::Archive::new(zstd::stream::read::Decoder::new(
+        File::open(path)?,
+    )?))
+}
+
+fn unknown_format(path: &Path) -> Error {
+    format_err!(
+        "not a .tar.zst or .zip archive or a repository snapshot: {}",
+        path.to_string_lossy()
+    )
+}
+
+/// Lists the files in the archive or repository snapshot index at `path`.
+pub fn list(path: &Path) -> Result<Vec<ArchiveEntry>, Error> {
+    let mut ret = vec![];
+
+    match Archive::of(path) {
+        Archive::Off if repository::is_index(path) => return repository::list(path),
+        Archive::TarZst => {
+            let mut archive = tar_reader(path)?;
+            for entry in archive.entries()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    ret.push(ArchiveEntry {
+                        path: entry.path()?.into_owned(),
+                        bytes: entry.header().size()?,
+                        error: None,
+                    });
+                }
+            }
+        }
+        Archive::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let entry = archive.by_index(i)?;
+                if entry.is_file() {
+                    ret.push(ArchiveEntry {
+                        path: PathBuf::from(entry.name()),
+                        bytes: entry.size(),
+                        error: None,
+                    });
+                }
+            }
+        }
+        Archive::Off => return Err(unknown_format(path)),
+    }
+
+    Ok(ret)
+}
+
+/// Extracts the archive or repository snapshot index at `path` under `to`,
+/// keeping the times and permissions of its files. Entries that would land
+/// outside `to` are skipped, and files of a snapshot with damaged chunks are
+/// returned with the error.
+pub fn extract(path: &Path, to: &Path) -> Result<Vec<ArchiveEntry>, Error> {
+    let mut ret = vec![];
+    fs::create_dir_all(to)?;
+
+    match Archive::of(path) {
+        Archive::Off if repository::is_index(path) => return repository::extract(path, to),
+        Archive::TarZst => {
+            let mut archive = tar_reader(path)?;
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let rel = entry.path()?.into_owned();
+                let bytes = entry.header().size()?;
+                let is_file = entry.header().entry_type().is_file();
+
+                if entry.unpack_in(to)? && is_file {
+                    ret.push(ArchiveEntry {
+                        path: rel,
+                        bytes,
+                        error: None,
+                    });
+                }
+            }
+        }
+        Archive::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i)?;
+                let rel = match entry.enclosed_name() {
+                    Some(rel) if entry.is_file() => rel.to_owned(),
+                    _ => continue,
+                };
+
+                let dest = to.join(&rel);
+                fs::create_dir_all(dest.parent().unwrap())?;
+                let mut file = File::create(&dest)?;
+                io::copy(&mut entry, &mut file)?;
+
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    if let Some(mode) = entry.unix_mode() {
+                        file.set_permissions(fs::Permissions::from_mode(mode))?;
+                    }
+                }
+
+                let time = entry.last_modified();
+                if let Some(time) = Local
+                    .with_ymd_and_hms(
+                        time.year() as i32,
+                        time.month() as u32,
+                        time.day() as u32,
+                        time.hour() as u32,
+                        time.minute() as u32,
+                        time.second() as u32,
+                    )
+                    .single()
+                {
+                    let time = time.into();
+                    file.set_times(FileTimes::new().set_accessed(time).set_modified(time))?;
+                }
+
+                ret.push(ArchiveEntry {
+                    path: rel,
+                    bytes: entry.size(),
+                    error: None,
+                });
+            }
+        }
+        Archive::Off => return Err(unknown_format(path)),
+    }
+
+    Ok(ret)
+}
+
+/// Prefix of the directories archives are extracted to for `restore` and
+/// `verify`.
+const EXTRACTED_PREFIX: &str = ".ubackup-extract-";
+
+/// Creates a new directory under `dir` with a random name, that only the
+/// current user can enter. Fails rather than reuse anything already there.
+fn create_private_dir(dir: &Path) -> Result<PathBuf, Error> {
+    let mut random = [0u8; 16];
+    getrandom::getrandom(&mut random)
+        .map_err(|e| format_err!("unable to get random bytes: {}", e))?;
+    let path = dir.join(format!("{}{}", EXTRACTED_PREFIX, hex::encode(random)));
+
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&path)?;
+    Ok(path)
+}
+
+/// An archive extracted to a temporary directory, removed when dropped.
+pub(crate) struct Extracted {
+    pub path: PathBuf,
+    /// The files that could not be extracted, and are missing from `path`.
+    pub failed: Vec<ArchiveEntry>,
+}
+
+impl Extracted {
+    /// Extracts `archive` next to it, where there is room for a backup, or
+    /// to the system's temporary directory if that is read-only.
+    pub fn new(archive: &Path) -> Result<Extracted, Error> {
+        let path = match archive.parent().map(create_private_dir) {
+            Some(Ok(path)) => path,
+            _ => create_private_dir(&std::env::temp_dir())?,
+        };
+
+        let mut extracted = Extracted {
+            path,
+            failed: vec![],
+        };
+        extracted.failed = extract(archive, &extracted.path)?
+            .into_iter()
+            .filter(|x| x.error.is_some())
+            .collect();
+        Ok(extracted)
+    }
+
+    /// Tells `observer` about every file that could not be extracted.
+    pub fn warn_failed(&self, observer: &mut dyn BackupObserver) {
+        for entry in &self.failed {
+            observer.warning(&format!(
+                "unable to extract {}: {}",
+                entry.path.to_string_lossy(),
+                entry.error.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+}
+
+impl Drop for Extracted {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SrcFile;
+    use crate::testing::TempDir;
+
+    fn planned(action: Action, src: PathBuf, dest: PathBuf) -> PlannedFile {
+        PlannedFile {
+            action,
+            src,
+            dest: Some(dest),
+            bytes: 0,
+            entry: SrcFile::default(),
+        }
+    }
+
+    fn round_trip(test: &str, extension: &str) {
+        let dir = TempDir::new(test);
+        let archive = dir.join(&format!("out/run.{}", extension));
+        let files = vec![
+            planned(
+                Action::Copy,
+                dir.write("src/a", "first"),
+                archive.join("docs/a"),
+            ),
+            planned(
+                Action::Copy,
+                dir.join("src/missing"),
+                archive.join("docs/missing"),
+            ),
+            planned(
+                Action::Copy,
+                dir.write("src/b", "second"),
+                archive.join("b"),
+            ),
+        ];
+        write(&archive, &files, |_, _| ()).unwrap();
+
+        let mut listed = list(&archive)
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.path, x.bytes))
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(
+            listed,
+            vec![(PathBuf::from("b"), 6), (PathBuf::from("docs/a"), 5)]
+        );
+
+        let extracted = extract(&archive, &dir.join("extracted")).unwrap();
+        assert_eq!(extracted.len(), 2);
+        assert_eq!(dir.read("extracted/docs/a").as_deref(), Some("first"));
+        assert_eq!(dir.read("extracted/b").as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn tar_zst_archives_round_trip() {
+        round_trip("archive-tar", "tar.zst");
+    }
+
+    #[test]
+    fn zip_archives_round_trip() {
+        round_trip("archive-zip", "zip");
+    }
+
+    #[test]
+    fn unknown_formats_are_refused() {
+        let dir = TempDir::new("archive-unknown");
+        let path = dir.write("run.tar", "");
+        assert!(list(&path).is_err());
+        assert!(extract(&path, &dir.join("extracted")).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use failure::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the temporary files written before being renamed into place.
+const PARTIAL_PREFIX: &str = ".ubackup-part-";
+
+/// The temporary sibling `path` is written to.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}{}", PARTIAL_PREFIX, name))
+}
+
+pub(crate) fn is_partial(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(PARTIAL_PREFIX))
+}
+
+/// Flushes `file`, written to `partial`, to the disk and renames it to `path`.
+///
+/// On Unix the directory is flushed too, so that the rename itself survives
+/// a crash or the drive being unplugged.
+fn commit(file: File, partial: &Path, path: &Path) -> Result<(), Error> {
+    file.sync_all()?;
+    drop(file);
+    fs::rename(partial, path)?;
+
+    #[cfg(unix)]
+    {
+        if let Some(parent) = path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `write` on a temporary sibling of `path` and commits the file it
+/// returns, removing the temporary file if anything fails.
+pub(crate) fn write<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&Path) -> Result<File, Error>,
+{
+    let partial = partial_path(path);
+    let ret = write(&partial).and_then(|file| commit(file, &partial, path));
+    if ret.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    ret
+}
+
+/// Writes `contents` to `path` through a temporary file.
+pub(crate) fn write_file(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    write(path, |partial| {
+        let mut file = File::create(partial)?;
+        file.write_all(contents)?;
+        Ok(file)
+    })
+}
+
+/// Removes the temporary files an interrupted run left under `root`.
+pub(crate) fn clean(root: &Path) -> Vec<PathBuf> {
+    let mut removed = vec![];
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return removed,
+    };
+
+    for entry in entries.filter_map(|x| x.ok()) {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => removed.extend(clean(&path)),
+            Ok(_) if is_partial(&path) && fs::remove_file(&path).is_ok() => removed.push(path),
+            _ => {}
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn files_are_replaced_whole() {
+        let dir = TempDir::new("atomic-replaced");
+        let path = dir.write("file", "old");
+        write_file(&path, b"new").unwrap();
+        assert_eq!(dir.read("file").as_deref(), Some("new"));
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[test]
+    fn failed_writes_keep_the_old_file() {
+        let dir = TempDir::new("atomic-failed");
+        let path = dir.write("file", "old");
+        let ret = write(&path, |partial| {
+            fs::write(partial, "half")?;
+            Err(format_err!("interrupted"))
+        });
+        assert!(ret.is_err());
+        assert_eq!(dir.read("file").as_deref(), Some("old"));
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[test]
+    fn partial_files_are_cleaned() {
+        let dir = TempDir::new("atomic-clean");
+        dir.write("file", "kept");
+        let partial = dir.write(&format!("sub/{}file", PARTIAL_PREFIX), "half");
+        assert!(is_partial(&partial));
+        assert_eq!(clean(&dir.path), vec![partial.clone()]);
+        assert!(!partial.exists());
+        assert_eq!(dir.read("file").as_deref(), Some("kept"));
+    }
+}
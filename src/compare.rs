@@ -0,0 +1,369 @@
+use crate::atomic;
+use crate::crypto::{self, Cipher};
+use crate::settings::Compare;
+use failure::Error;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+const CACHE_FILE: &str = ".ubackup-hashes.yaml";
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+struct CachedHash {
+    size: u64,
+    mtime: (u64, u32),
+    hash: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheFile {
+    compare: Compare,
+    files: BTreeMap<String, CachedHash>,
+}
+
+fn mtime(md: &Metadata) -> Result<(u64, u32), Error> {
+    let mtime = md.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+/// Hashes what is written to it.
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(compare: Compare) -> Hasher {
+        match compare {
+            Compare::Sha256 => Hasher::Sha256(Sha256::new()),
+            _ => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(buf),
+            Hasher::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Passes on what is read from `inner`, hashing it on the way if there is a
+/// hasher.
+pub(crate) struct HashingReader<'a, R> {
+    inner: R,
+    hasher: Option<&'a mut Hasher>,
+}
+
+impl<'a, R: Read> HashingReader<'a, R> {
+    pub fn new(inner: R, hasher: Option<&'a mut Hasher>) -> Self {
+        HashingReader { inner, hasher }
+    }
+}
+
+impl<'a, R: Read> Read for HashingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+pub(crate) fn hash_reader<R: Read>(mut reader: R, compare: Compare) -> Result<String, Error> {
+    let mut hasher = Hasher::new(compare);
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+pub(crate) fn hash_file(path: &Path, compare: Compare) -> Result<String, Error> {
+    hash_reader(io::BufReader::new(File::open(path)?), compare)
+}
+
+/// Decides whether a destination file is up to date with its source.
+///
+/// For the hashing strategies, destination hashes are cached in a file at the
+/// destination root, keyed by size and mtime, so an unchanged backup is only
+/// read once. The cache is locked, so workers can share a `Comparator`.
+///
+/// On an encrypted destination, sizes and hashes are those of the decrypted
+/// contents.
+pub(crate) struct Comparator {
+    compare: Compare,
+    root: PathBuf,
+    cache: Mutex<BTreeMap<String, CachedHash>>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl Comparator {
+    pub fn load(root: &Path, compare: Compare) -> Comparator {
+        let mut cache = BTreeMap::new();
+
+        if compare == Compare::Blake3 || compare == Compare::Sha256 {
+            if let Ok(file) = File::open(root.join(CACHE_FILE)) {
+                if let Ok(file) = serde_yaml::from_reader::<_, CacheFile>(file) {
+                    if file.compare == compare {
+                        cache = file.files;
+                    }
+                }
+            }
+        }
+
+        Comparator {
+            compare,
+            root: root.to_owned(),
+            cache: Mutex::new(cache),
+            cipher: None,
+        }
+    }
+
+    /// Compares against copies encrypted with `cipher`.
+    pub fn encrypted(mut self, cipher: Option<Arc<Cipher>>) -> Comparator {
+        self.cipher = cipher;
+        self
+    }
+
+    /// `hash`, keyed on an encrypted destination.
+    fn keyed(&self, hash: String) -> String {
+        match self.cipher {
+            Some(ref cipher) => cipher.key_hash(&hash),
+            None => hash,
+        }
+    }
+
+    /// The hash of `src`, keyed on an encrypted destination.
+    fn source_hash(&self, src: &Path) -> Result<String, Error> {
+        Ok(self.keyed(hash_file(src, self.compare)?))
+    }
+
+    /// Whether `dest_md` has the size of a copy of `src_md`.
+    fn same_size(&self, src_md: &Metadata, dest_md: &Metadata) -> bool {
+        match self.cipher {
+            Some(_) => dest_md.len() == crypto::encrypted_len(src_md.len()),
+            None => dest_md.len() == src_md.len(),
+        }
+    }
+
+    fn hashing(&self) -> bool {
+        self.compare == Compare::Blake3 || self.compare == Compare::Sha256
+    }
+
+    fn key(&self, dest: &Path) -> String {
+        dest.strip_prefix(&self.root)
+            .unwrap_or(dest)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn insert(&self, key: String, cached: CachedHash) {
+        self.cache.lock().unwrap().insert(key, cached);
+    }
+
+    fn dest_hash(&self, dest: &Path, dest_md: &Metadata) -> Result<String, Error> {
+        let key = self.key(dest);
+        let size = dest_md.len();
+        let mtime = mtime(dest_md)?;
+
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            if cached.size == size && cached.mtime == mtime {
+                return Ok(cached.hash.clone());
+            }
+        }
+
+        let hash = match self.cipher {
+            Some(ref cipher) => {
+                let mut hasher = Hasher::new(self.compare);
+                cipher.decrypt(io::BufReader::new(File::open(dest)?), &mut hasher)?;
+                cipher.key_hash(&hasher.finish())
+            }
+            None => hash_file(dest, self.compare)?,
+        };
+        self.insert(
+            key,
+            CachedHash {
+                size,
+                mtime,
+                hash: hash.clone(),
+            },
+        );
+        Ok(hash)
+    }
+
+    pub fn is_up_to_date(
+        &self,
+        src: &Path,
+        src_md: &Metadata,
+        dest: &Path,
+        dest_md: &Metadata,
+    ) -> Result<bool, Error> {
+        match self.compare {
+            // Sizes tell plain copies made before encryption was enabled.
+            Compare::Mtime if self.cipher.is_some() => {
+                Ok(self.same_size(src_md, dest_md) && dest_md.modified()? >= src_md.modified()?)
+            }
+            Compare::Mtime => Ok(dest_md.modified()? >= src_md.modified()?),
+            Compare::MtimeSize => {
+                Ok(self.same_size(src_md, dest_md) && dest_md.modified()? >= src_md.modified()?)
+            }
+            Compare::Blake3 | Compare::Sha256 => Ok(self.same_size(src_md, dest_md)
+                && self.source_hash(src)? == self.dest_hash(dest, dest_md)?),
+        }
+    }
+
+    /// A hasher for the source of a copy, if the hash of the copy is cached.
+    /// Passing what is copied through it saves reading the source twice.
+    pub fn hasher(&self) -> Option<Hasher> {
+        if self.hashing() {
+            Some(Hasher::new(self.compare))
+        } else {
+            None
+        }
+    }
+
+    /// Records that `dest` has just been copied from a source, hashed by
+    /// `hasher` as it was read.
+    pub fn copied(&self, dest: &Path, hasher: Option<Hasher>) -> Result<(), Error> {
+        let hash = match hasher {
+            Some(hasher) => self.keyed(hasher.finish()),
+            None => return Ok(()),
+        };
+        let dest_md = dest.metadata()?;
+
+        self.insert(
+            self.key(dest),
+            CachedHash {
+                size: dest_md.len(),
+                mtime: mtime(&dest_md)?,
+                hash,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn save(self) -> Result<(), Error> {
+        if !self.hashing() {
+            return Ok(());
+        }
+
+        let root = self.root;
+        let mut cache = self.cache.into_inner().unwrap();
+        cache.retain(|key, _| root.join(key).is_file());
+
+        fs::create_dir_all(&root)?;
+        atomic::write_file(
+            &root.join(CACHE_FILE),
+            serde_yaml::to_string(&CacheFile {
+                compare: self.compare,
+                files: cache,
+            })?
+            .as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+    use crate::Settings;
+    use std::time::{Duration, SystemTime};
+
+    fn settings(dir: &TempDir, compare: Compare) -> Settings {
+        let mut settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        );
+        settings.config.compare = compare;
+        settings
+    }
+
+    /// Backs up `src/a`, then writes `contents` to it with the mtime `mtime`.
+    fn changed(test: &str, contents: &str, mtime: SystemTime) -> TempDir {
+        let dir = TempDir::new(test);
+        dir.write("src/a", "a");
+        testing::backup(settings(&dir, Compare::Mtime));
+
+        let src = dir.write("src/a", contents);
+        File::options()
+            .write(true)
+            .open(src)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        dir
+    }
+
+    #[test]
+    fn hashes_skip_touched_files() {
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let dir = changed("compare-touched", "a", later);
+
+        assert_eq!(testing::backup(settings(&dir, Compare::Blake3)).skips, 1);
+        assert_eq!(testing::backup(settings(&dir, Compare::Sha256)).skips, 1);
+        assert_eq!(testing::backup(settings(&dir, Compare::Mtime)).copies, 1);
+    }
+
+    #[test]
+    fn hashes_copy_changes_mtimes_miss() {
+        let earlier = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let dir = changed("compare-same-size", "b", earlier);
+
+        assert_eq!(testing::backup(settings(&dir, Compare::MtimeSize)).skips, 1);
+        assert_eq!(testing::backup(settings(&dir, Compare::Blake3)).copies, 1);
+        assert_eq!(dir.read("out/copy/a").as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn destination_hashes_are_cached() {
+        let dir = TempDir::new("compare-cache");
+        dir.write("src/a", "a");
+        testing::backup(settings(&dir, Compare::Blake3));
+
+        let cache = fs::read_to_string(dir.join("out").join(CACHE_FILE)).unwrap();
+        assert!(cache.contains("copy/a"));
+        assert!(cache.contains(blake3::hash(b"a").to_hex().as_str()));
+    }
+
+    #[test]
+    fn copies_are_hashed_as_they_are_read() {
+        for compare in [Compare::Blake3, Compare::Sha256] {
+            let mut hasher = Hasher::new(compare);
+            let mut copy = vec![];
+            io::copy(
+                &mut HashingReader::new(&b"contents"[..], Some(&mut hasher)),
+                &mut copy,
+            )
+            .unwrap();
+
+            assert_eq!(copy, b"contents");
+            assert_eq!(
+                hasher.finish(),
+                hash_reader(&b"contents"[..], compare).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,606 @@
+use crate::atomic;
+use crate::settings::Encryption;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use data_encoding::BASE32_NOPAD;
+use failure::Error;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Holds the salt and key check of an encrypted destination, at its root.
+const HEADER_FILE: &str = ".ubackup-encryption.yaml";
+
+const MAGIC: &[u8; 8] = b"UBKENC01";
+const CHUNK: usize = 64 * 1024;
+const NONCE_PREFIX: usize = 16;
+const TAG: usize = 16;
+const HEADER_LEN: u64 = (MAGIC.len() + NONCE_PREFIX) as u64;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct Argon2Params {
+    memory: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+/// The salt and key check of an encrypted destination. A plan carries the
+/// header of a destination that doesn't have one yet, so that `apply` writes
+/// the header the plan's names were encrypted under.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EncryptionHeader {
+    /// Salt of the passphrase, in hex.
+    salt: String,
+    argon2: Argon2Params,
+    /// Derived from the key, to tell a wrong passphrase or key file.
+    check: String,
+    filenames: bool,
+}
+
+fn random(len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; len];
+    getrandom::getrandom(&mut buf).map_err(|e| format_err!("unable to get random bytes: {}", e))?;
+    Ok(buf)
+}
+
+fn master_key(settings: &Encryption, header: &EncryptionHeader) -> Result<[u8; 32], Error> {
+    if let Some(ref key_file) = settings.key_file {
+        let contents = fs::read(key_file).map_err(|e| {
+            format_err!(
+                "unable to read key file: {}: {}",
+                key_file.to_string_lossy(),
+                e
+            )
+        })?;
+        return Ok(blake3::derive_key("ubackup key file", &contents));
+    }
+
+    let passphrase = match settings.passphrase {
+        Some(ref passphrase) => passphrase.clone(),
+        None => env::var("UBACKUP_PASSPHRASE")
+            .map_err(|_| format_err!("encryption needs a passphrase or key file"))?,
+    };
+
+    let params = Params::new(
+        header.argon2.memory,
+        header.argon2.iterations,
+        header.argon2.parallelism,
+        Some(32),
+    )
+    .map_err(|e| format_err!("encryption header is invalid: {}", e))?;
+
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &hex::decode(&header.salt)?, &mut key)
+        .map_err(|e| format_err!("unable to derive key: {}", e))?;
+    Ok(key)
+}
+
+fn aead_error(_: chacha20poly1305::aead::Error) -> Error {
+    format_err!("decryption failed: the file is corrupt or the key is wrong")
+}
+
+/// Encrypts file contents and, optionally, names for one destination.
+///
+/// Contents are split in chunks sealed with XChaCha20-Poly1305, under a
+/// random nonce prefix and the chunk counter, so chunks can't be reordered,
+/// dropped or truncated unnoticed. Names are sealed with a nonce derived from
+/// the name itself, so the same name always encrypts the same way and paths
+/// can still be looked up.
+pub(crate) struct Cipher {
+    content: XChaCha20Poly1305,
+    hashes: [u8; 32],
+    names: Option<(XChaCha20Poly1305, [u8; 32])>,
+}
+
+/// Reads the header of the destination at `root`, if it has one.
+fn read_header(root: &Path) -> Result<Option<EncryptionHeader>, Error> {
+    let path = root.join(HEADER_FILE);
+    match fs::read_to_string(&path) {
+        Ok(text) => Ok(Some(serde_yaml::from_str(&text)?)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format_err!(
+            "unable to read encryption header: {}: {}",
+            path.to_string_lossy(),
+            e
+        )),
+    }
+}
+
+impl Cipher {
+    /// Loads the key for the destination at `root`. A destination without a
+    /// header gets a new one, which is returned for the plan to carry. Nothing
+    /// is written.
+    pub fn load(
+        root: &Path,
+        settings: &Encryption,
+    ) -> Result<(Cipher, Option<EncryptionHeader>), Error> {
+        if let Some(header) = read_header(root)? {
+            return Ok((Cipher::new(root, settings, &header)?, None));
+        }
+
+        let params = Params::default();
+        let mut header = EncryptionHeader {
+            salt: hex::encode(random(16)?),
+            argon2: Argon2Params {
+                memory: params.m_cost(),
+                iterations: params.t_cost(),
+                parallelism: params.p_cost(),
+            },
+            check: String::new(),
+            filenames: settings.filenames,
+        };
+        header.check = Cipher::check(&master_key(settings, &header)?);
+
+        Ok((Cipher::new(root, settings, &header)?, Some(header)))
+    }
+
+    /// Loads the key for the destination at `root` to apply a plan, writing
+    /// the header `planned` carries if the destination has none yet.
+    pub fn create(
+        root: &Path,
+        settings: &Encryption,
+        planned: Option<&EncryptionHeader>,
+    ) -> Result<Cipher, Error> {
+        match (read_header(root)?, planned) {
+            (Some(ref header), Some(planned)) if header != planned => Err(format_err!(
+                "encryption header changed since the plan was made: {}",
+                root.to_string_lossy()
+            )),
+            (Some(header), _) => Cipher::new(root, settings, &header),
+            (None, Some(header)) => {
+                let cipher = Cipher::new(root, settings, header)?;
+                fs::create_dir_all(root)?;
+                atomic::write_file(
+                    &root.join(HEADER_FILE),
+                    serde_yaml::to_string(header)?.as_bytes(),
+                )?;
+                Ok(cipher)
+            }
+            (None, None) => Err(format_err!(
+                "encryption header is missing: {}",
+                root.to_string_lossy()
+            )),
+        }
+    }
+
+    /// Loads the key for the encrypted destination holding `path`, looking
+    /// for its header in `path` and the directories above it. Returns `None`
+    /// if `path` isn't encrypted.
+    pub fn find(path: &Path, settings: &Encryption) -> Result<Option<Cipher>, Error> {
+        for dir in path.ancestors() {
+            if let Some(header) = read_header(dir)? {
+                return Ok(Some(Cipher::new(dir, settings, &header)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Tells a wrong passphrase or key file from the right one.
+    fn check(master: &[u8; 32]) -> String {
+        hex::encode(blake3::derive_key("ubackup key check", master))
+    }
+
+    /// Derives the keys for the destination at `root` from `header`.
+    fn new(root: &Path, settings: &Encryption, header: &EncryptionHeader) -> Result<Cipher, Error> {
+        let master = master_key(settings, header)?;
+        if header.check != Cipher::check(&master) {
+            return Err(format_err!(
+                "wrong passphrase or key file for {}",
+                root.to_string_lossy()
+            ));
+        }
+
+        let key = |context| Key::from(blake3::derive_key(context, &master));
+        Ok(Cipher {
+            content: XChaCha20Poly1305::new(&key("ubackup contents")),
+            hashes: blake3::derive_key("ubackup hashes", &master),
+            names: if header.filenames {
+                Some((
+                    XChaCha20Poly1305::new(&key("ubackup names")),
+                    blake3::derive_key("ubackup name nonces", &master),
+                ))
+            } else {
+                None
+            },
+        })
+    }
+
+    fn nonce(prefix: &[u8], counter: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..NONCE_PREFIX].copy_from_slice(prefix);
+        nonce[NONCE_PREFIX..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn aad(last: bool) -> &'static [u8] {
+        if last {
+            b"last"
+        } else {
+            b""
+        }
+    }
+
+    /// Encrypts everything `reader` yields to `writer`.
+    pub fn encrypt<R: Read, W: Write>(&self, mut reader: R, mut writer: W) -> Result<(), Error> {
+        let prefix = random(NONCE_PREFIX)?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&prefix)?;
+
+        let mut buf = vec![0u8; CHUNK];
+        let mut counter = 0;
+        loop {
+            let len = read_full(&mut reader, &mut buf)?;
+            let last = len < CHUNK;
+            let sealed = self
+                .content
+                .encrypt(
+                    &Cipher::nonce(&prefix, counter),
+                    Payload {
+                        msg: &buf[..len],
+                        aad: Cipher::aad(last),
+                    },
+                )
+                .map_err(|_| format_err!("encryption failed"))?;
+            writer.write_all(&sealed)?;
+
+            if last {
+                return Ok(writer.flush()?);
+            }
+            counter += 1;
+        }
+    }
+
+    /// Decrypts what `encrypt` wrote to `reader` into `writer`.
+    pub fn decrypt<R: Read, W: Write>(&self, mut reader: R, mut writer: W) -> Result<(), Error> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        if read_full(&mut reader, &mut header)? < header.len() || &header[..MAGIC.len()] != MAGIC {
+            return Err(format_err!("file is not encrypted by ubackup"));
+        }
+        let prefix = &header[MAGIC.len()..];
+
+        let mut buf = vec![0u8; CHUNK + TAG];
+        let mut counter = 0;
+        loop {
+            let len = read_full(&mut reader, &mut buf)?;
+            let last = len < buf.len();
+            let opened = self
+                .content
+                .decrypt(
+                    &Cipher::nonce(prefix, counter),
+                    Payload {
+                        msg: &buf[..len],
+                        aad: Cipher::aad(last),
+                    },
+                )
+                .map_err(aead_error)?;
+            writer.write_all(&opened)?;
+
+            if last {
+                return Ok(writer.flush()?);
+            }
+            counter += 1;
+        }
+    }
+
+    /// Keys a hash of decrypted contents, so that the hashes kept on the
+    /// destination don't reveal which files it holds.
+    pub fn key_hash(&self, hash: &str) -> String {
+        blake3::keyed_hash(&self.hashes, hash.as_bytes())
+            .to_hex()
+            .to_string()
+    }
+
+    /// Whether names are encrypted.
+    pub fn encrypts_names(&self) -> bool {
+        self.names.is_some()
+    }
+
+    /// Encrypts one file or directory name.
+    pub fn encrypt_name(&self, name: &str) -> Result<String, Error> {
+        let (ref cipher, ref nonce_key) = match self.names {
+            Some(ref names) => names,
+            None => return Ok(name.to_owned()),
+        };
+
+        let hash = blake3::keyed_hash(nonce_key, name.as_bytes());
+        let nonce = XNonce::from_slice(&hash.as_bytes()[..24]);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            cipher
+                .encrypt(nonce, name.as_bytes())
+                .map_err(|_| format_err!("encryption failed"))?,
+        );
+
+        let encoded = BASE32_NOPAD.encode(&sealed).to_lowercase();
+        if encoded.len() > 255 {
+            return Err(format_err!("name is too long to encrypt: {}", name));
+        }
+        Ok(encoded)
+    }
+
+    /// Decrypts a name `encrypt_name` returned, or `None` if it isn't one.
+    pub fn decrypt_name(&self, name: &str) -> Option<String> {
+        let (ref cipher, _) = match self.names {
+            Some(ref names) => names,
+            None => return Some(name.to_owned()),
+        };
+
+        let sealed = BASE32_NOPAD.decode(name.to_uppercase().as_bytes()).ok()?;
+        if sealed.len() < 24 {
+            return None;
+        }
+
+        let (nonce, sealed) = sealed.split_at(24);
+        let opened = cipher.decrypt(XNonce::from_slice(nonce), sealed).ok()?;
+        String::from_utf8(opened).ok()
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// The size of a file of `len` bytes once encrypted.
+pub(crate) fn encrypted_len(len: u64) -> u64 {
+    let chunks = len / CHUNK as u64 + 1;
+    HEADER_LEN + len + chunks * TAG as u64
+}
+
+/// Maps names between sources and a backup tree whose names may be encrypted.
+#[derive(Default)]
+pub(crate) struct Names {
+    cipher: Option<Arc<Cipher>>,
+    /// Whether files go from the backup tree to their sources.
+    restore: bool,
+}
+
+impl Names {
+    /// Names for a backup to a destination encrypted with `cipher`.
+    pub fn backup(cipher: Option<Arc<Cipher>>) -> Names {
+        Names {
+            cipher: cipher.filter(|cipher| cipher.encrypts_names()),
+            restore: false,
+        }
+    }
+
+    /// Names for a restore from a backup encrypted with `cipher`.
+    pub fn restore(cipher: Option<Arc<Cipher>>) -> Names {
+        Names {
+            restore: true,
+            ..Names::backup(cipher)
+        }
+    }
+
+    /// The cipher of the backed up names, if they are encrypted.
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_deref()
+    }
+
+    /// The name a copy of `name` gets.
+    pub fn dest_name(&self, name: &OsStr) -> Result<OsString, Error> {
+        match self.cipher {
+            Some(ref cipher) if self.restore => {
+                match cipher.decrypt_name(&name.to_string_lossy()) {
+                    Some(name) => Ok(OsString::from(name)),
+                    None => Err(format_err!(
+                        "name is not encrypted: {}",
+                        name.to_string_lossy()
+                    )),
+                }
+            }
+            Some(ref cipher) => Ok(OsString::from(
+                cipher.encrypt_name(&name.to_string_lossy())?,
+            )),
+            None => Ok(name.to_owned()),
+        }
+    }
+
+    /// The relative path a copy of `path` gets.
+    pub fn dest_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        let mut ret = PathBuf::new();
+
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => ret.push(self.dest_name(name)?),
+                component => ret.push(component.as_os_str()),
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// The source name of the backed up `name`, or `None` if it was not
+    /// written by ubackup.
+    pub fn source_name(&self, name: &OsStr) -> Option<OsString> {
+        match self.cipher {
+            Some(ref cipher) => cipher
+                .decrypt_name(&name.to_string_lossy())
+                .map(OsString::from),
+            None => Some(name.to_owned()),
+        }
+    }
+
+    /// The source path of the backed up relative `path`, keeping the names
+    /// that can't be decrypted.
+    pub fn source_path(&self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|x| {
+                self.source_name(x.as_os_str())
+                    .unwrap_or_else(|| x.as_os_str().to_owned())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(passphrase: &str) -> Encryption {
+        Encryption {
+            enabled: true,
+            passphrase: Some(passphrase.to_owned()),
+            key_file: None,
+            filenames: true,
+        }
+    }
+
+    /// A header with cheap Argon2 parameters, so the tests run quickly.
+    fn header(settings: &Encryption) -> EncryptionHeader {
+        let mut header = EncryptionHeader {
+            salt: hex::encode(random(16).unwrap()),
+            argon2: Argon2Params {
+                memory: 64,
+                iterations: 1,
+                parallelism: 1,
+            },
+            check: String::new(),
+            filenames: settings.filenames,
+        };
+        header.check = Cipher::check(&master_key(settings, &header).unwrap());
+        header
+    }
+
+    fn cipher(passphrase: &str) -> Cipher {
+        let settings = settings(passphrase);
+        Cipher::new(Path::new("/backup"), &settings, &header(&settings)).unwrap()
+    }
+
+    fn encrypt(cipher: &Cipher, plain: &[u8]) -> Vec<u8> {
+        let mut sealed = vec![];
+        cipher.encrypt(plain, &mut sealed).unwrap();
+        sealed
+    }
+
+    fn decrypt(cipher: &Cipher, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut plain = vec![];
+        cipher.decrypt(sealed, &mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn round_trip() {
+        let cipher = cipher("hunter2");
+
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 3 * CHUNK + 5] {
+            let plain: Vec<u8> = (0..len).map(|x| x as u8).collect();
+            let sealed = encrypt(&cipher, &plain);
+
+            assert_eq!(sealed.len() as u64, encrypted_len(len as u64));
+            assert_eq!(decrypt(&cipher, &sealed).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn same_contents_encrypt_differently() {
+        let cipher = cipher("hunter2");
+        assert_ne!(encrypt(&cipher, b"secret"), encrypt(&cipher, b"secret"));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let header = header(&settings("hunter2"));
+
+        assert!(Cipher::new(Path::new("/backup"), &settings("hunter2"), &header).is_ok());
+        assert!(Cipher::new(Path::new("/backup"), &settings("hunter3"), &header).is_err());
+    }
+
+    #[test]
+    fn wrong_key() {
+        // Each header has its own salt, so the same passphrase gives another key.
+        let sealed = encrypt(&cipher("hunter2"), b"secret");
+        assert!(decrypt(&cipher("hunter2"), &sealed).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let cipher = cipher("hunter2");
+        let plain = vec![7u8; 2 * CHUNK + 10];
+        let sealed = encrypt(&cipher, &plain);
+
+        // Into the last chunk, without the last chunk, and into the header.
+        for len in [
+            sealed.len() - 1,
+            HEADER_LEN as usize + 2 * (CHUNK + TAG),
+            HEADER_LEN as usize + CHUNK + TAG,
+            HEADER_LEN as usize,
+            4,
+        ] {
+            assert!(decrypt(&cipher, &sealed[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn whole_chunks_truncated() {
+        let cipher = cipher("hunter2");
+        let sealed = encrypt(&cipher, &vec![7u8; 2 * CHUNK]);
+
+        // The empty last chunk marks the end.
+        assert!(decrypt(&cipher, &sealed[..sealed.len() - TAG]).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let cipher = cipher("hunter2");
+        let sealed = encrypt(&cipher, &vec![7u8; CHUNK + 10]);
+
+        for i in [0, MAGIC.len(), HEADER_LEN as usize, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(decrypt(&cipher, &tampered).is_err(), "{}", i);
+        }
+    }
+
+    #[test]
+    fn reordered_chunks() {
+        let cipher = cipher("hunter2");
+        let plain: Vec<u8> = (0..3 * CHUNK).map(|x| (x / CHUNK) as u8).collect();
+        let mut sealed = encrypt(&cipher, &plain);
+
+        let first = HEADER_LEN as usize;
+        let second = first + CHUNK + TAG;
+        let chunk = sealed[first..second].to_vec();
+        sealed.copy_within(second..second + CHUNK + TAG, first);
+        sealed[second..second + CHUNK + TAG].copy_from_slice(&chunk);
+
+        assert!(decrypt(&cipher, &sealed).is_err());
+    }
+
+    #[test]
+    fn names() {
+        let ours = cipher("hunter2");
+        let sealed = ours.encrypt_name("Report 2024.txt").unwrap();
+
+        assert_ne!(sealed, "Report 2024.txt");
+        assert_eq!(ours.encrypt_name("Report 2024.txt").unwrap(), sealed);
+        assert_eq!(
+            ours.decrypt_name(&sealed).as_deref(),
+            Some("Report 2024.txt")
+        );
+        assert_eq!(ours.decrypt_name("Report 2024.txt"), None);
+        assert_eq!(cipher("hunter3").decrypt_name(&sealed), None);
+    }
+
+    #[test]
+    fn load_writes_nothing() {
+        let root = Path::new("/nonexistent/ubackup");
+        let (_, header) = Cipher::load(root, &settings("hunter2")).unwrap();
+
+        assert!(header.is_some());
+        assert!(!root.exists());
+    }
+}
@@ -0,0 +1,219 @@
+use failure::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use systemstat::{ByteSize, Platform, System};
+
+/// How `DestDrive::label` picks the drive to back up to.
+#[derive(Debug, PartialEq)]
+enum Selector<'a> {
+    CurrentDrive,
+    Label(&'a str),
+    Uuid(&'a str),
+    MountPoint(&'a str),
+    Device(&'a str),
+    Marker(&'a str),
+    /// A selector without a prefix: a device, or failing that a label.
+    Any(&'a str),
+}
+
+impl<'a> Selector<'a> {
+    fn parse(label: &'a str) -> Selector<'a> {
+        if label == "$CURRENTDRIVE" {
+            return Selector::CurrentDrive;
+        }
+
+        match label.split_once(':') {
+            Some(("label", value)) => Selector::Label(value),
+            Some(("uuid", value)) => Selector::Uuid(value),
+            Some(("mountpoint", value)) => Selector::MountPoint(value),
+            Some(("device", value)) => Selector::Device(value),
+            Some(("marker", value)) => Selector::Marker(value),
+            _ => Selector::Any(label),
+        }
+    }
+}
+
+fn get_current_drive() -> Option<String> {
+    if let Ok(path) = std::env::current_dir() {
+        let mut components = path.components();
+        let mut path = PathBuf::new();
+
+        while !path.ends_with(std::path::MAIN_SEPARATOR.to_string()) {
+            match components.next() {
+                Some(component) => match component {
+                    Component::RootDir => path.push(std::path::MAIN_SEPARATOR.to_string()),
+                    Component::Prefix(prefix) => path.push(prefix.as_os_str()),
+                    _ => {}
+                },
+                None => return None,
+            }
+        }
+
+        return Some(path.to_string_lossy().into_owned());
+    }
+    None
+}
+
+/// The mounted filesystems, as (mounted from, mounted on).
+fn mounts() -> Vec<(String, String)> {
+    match System::new().mounts() {
+        Ok(mounts) => mounts
+            .into_iter()
+            .map(|mount| (mount.fs_mounted_from, mount.fs_mounted_on))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// The space available to unprivileged users on the filesystem holding `path`.
+pub(crate) fn free_space(path: &Path) -> Option<ByteSize> {
+    System::new()
+        .mounts()
+        .ok()?
+        .into_iter()
+        .filter(|mount| path.starts_with(&mount.fs_mounted_on))
+        .max_by_key(|mount| mount.fs_mounted_on.len())
+        .map(|mount| mount.avail)
+}
+
+/// Resolves symlinks such as `/dev/disk/by-label/...` to the device itself.
+fn canonical_device(device: &str) -> PathBuf {
+    if !Path::new(device).is_absolute() {
+        return PathBuf::from(device);
+    }
+
+    fs::canonicalize(device).unwrap_or_else(|_| PathBuf::from(device))
+}
+
+fn get_drive_by_device(device: &str) -> Option<String> {
+    let device = canonical_device(device);
+
+    mounts()
+        .into_iter()
+        .find(|(from, _)| canonical_device(from) == device)
+        .map(|(_, on)| on)
+}
+
+/// Escapes a name the way udev does for the links in `/dev/disk`.
+fn udev_escape(name: &str) -> String {
+    let mut ret = String::new();
+
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"#+-.:=@_".contains(&byte) {
+            ret.push(byte as char);
+        } else {
+            ret.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+
+    ret
+}
+
+fn get_drive_by_link(dir: &str, name: &str) -> Option<String> {
+    let link = Path::new("/dev/disk").join(dir).join(udev_escape(name));
+    if !link.exists() {
+        return None;
+    }
+
+    get_drive_by_device(&link.to_string_lossy())
+}
+
+fn get_drive_by_label(label: &str) -> Option<String> {
+    if cfg!(target_os = "linux") {
+        get_drive_by_link("by-label", label)
+    } else {
+        // Elsewhere systemstat reports the volume name as the mount source.
+        get_drive_by_device(label)
+    }
+}
+
+/// The one drive with `marker` in its root. Several are an error, so that a
+/// stray copy of the marker never decides where backups go.
+fn get_drive_by_marker(marker: &str) -> Result<Option<String>, Error> {
+    let mut found: Vec<String> = mounts()
+        .into_iter()
+        .map(|(_, on)| on)
+        .filter(|on| Path::new(on).join(marker).is_file())
+        .collect();
+    found.sort();
+    found.dedup();
+
+    match found.len() {
+        0 => Ok(None),
+        1 => Ok(found.pop()),
+        _ => Err(format_err!(
+            "several drives contain marker {}: {}",
+            marker,
+            found.join(", ")
+        )),
+    }
+}
+
+/// Finds the mount point of the drive `label` selects.
+pub(crate) fn get_drive(label: &str) -> Result<String, Error> {
+    let drive = match Selector::parse(label) {
+        Selector::CurrentDrive => {
+            return get_current_drive()
+                .ok_or_else(|| format_err!("unable to detect current drive"));
+        }
+        Selector::Label(label) => get_drive_by_label(label),
+        Selector::Uuid(uuid) if cfg!(target_os = "linux") => get_drive_by_link("by-uuid", uuid),
+        Selector::Uuid(_) => {
+            return Err(format_err!("uuid selectors are only supported on Linux"));
+        }
+        Selector::MountPoint(path) => mounts()
+            .into_iter()
+            .map(|(_, on)| on)
+            .find(|on| Path::new(on) == Path::new(path)),
+        Selector::Device(device) => get_drive_by_device(device),
+        Selector::Marker(marker) => get_drive_by_marker(marker)?,
+        Selector::Any(label) => get_drive_by_device(label).or_else(|| get_drive_by_label(label)),
+    };
+
+    drive.ok_or_else(|| format_err!("unable to find drive: {}", label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selectors() {
+        assert_eq!(Selector::parse("$CURRENTDRIVE"), Selector::CurrentDrive);
+        assert_eq!(Selector::parse("label:Backup"), Selector::Label("Backup"));
+        assert_eq!(
+            Selector::parse("uuid:1234-ABCD"),
+            Selector::Uuid("1234-ABCD")
+        );
+        assert_eq!(
+            Selector::parse("mountpoint:/mnt/a:b"),
+            Selector::MountPoint("/mnt/a:b")
+        );
+        assert_eq!(
+            Selector::parse("device:/dev/sdb1"),
+            Selector::Device("/dev/sdb1")
+        );
+        assert_eq!(
+            Selector::parse("marker:.backup"),
+            Selector::Marker(".backup")
+        );
+        assert_eq!(Selector::parse("Backup"), Selector::Any("Backup"));
+        assert_eq!(Selector::parse("C:"), Selector::Any("C:"));
+    }
+
+    #[test]
+    fn labels_are_escaped_like_udev() {
+        assert_eq!(udev_escape("My Backup"), "My\\x20Backup");
+        assert_eq!(udev_escape("a/b"), "a\\x2fb");
+        assert_eq!(udev_escape("USB-2024_1.0"), "USB-2024_1.0");
+    }
+
+    #[test]
+    fn drives_are_found_by_mount_point() {
+        for (_, on) in mounts().into_iter().take(5) {
+            assert_eq!(get_drive(&format!("mountpoint:{}", on)).unwrap(), on);
+        }
+        assert!(get_drive("mountpoint:/nonexistent/ubackup").is_err());
+        assert!(get_drive("marker:.ubackup-test-marker-that-does-not-exist").is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use failure::Error;
+use regex::Regex;
+use std::path::{Component, Path};
+
+#[derive(Debug)]
+struct Pattern {
+    re: Regex,
+    negate: bool,
+    dir_only: bool,
+}
+
+/// A list of gitignore-style patterns, matched against paths relative to the
+/// directory a `from` glob matched.
+///
+/// Patterns without a `/` match a name at any depth, others are anchored to
+/// that directory. `*`, `?`, `[...]` and `**` work as in `.gitignore`, a
+/// trailing `/` only matches directories, and `!` re-includes a path. The last
+/// matching pattern wins. Patterns match case-insensitively only where the
+/// entry asks for it, as `from` does.
+#[derive(Debug, Default)]
+pub(crate) struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+fn translate(glob: &str) -> String {
+    let mut re = String::new();
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                let mut class = String::new();
+                let mut closed = false;
+
+                if let Some(&'!') = chars.peek() {
+                    chars.next();
+                    class.push('^');
+                }
+
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+
+                if closed {
+                    re.push('[');
+                    re.push_str(&class);
+                    re.push(']');
+                } else {
+                    re.push_str(&regex::escape(&format!("[{}", class)));
+                }
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    re
+}
+
+impl Ignore {
+    pub fn new<'a, I: IntoIterator<Item = &'a String>>(
+        patterns: I,
+        case_sensitive: bool,
+    ) -> Result<Ignore, Error> {
+        let mut ret = Ignore::default();
+        let flags = if case_sensitive { "" } else { "(?i)" };
+
+        for pattern in patterns {
+            let pattern = pattern.trim();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+
+            let (negate, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+
+            let dir_only = pattern.ends_with('/');
+            let pattern = pattern.trim_end_matches('/');
+
+            let re = if pattern.contains('/') {
+                format!("{}^{}$", flags, translate(pattern.trim_start_matches('/')))
+            } else {
+                format!("{}^(?:.*/)?{}$", flags, translate(pattern))
+            };
+
+            ret.patterns.push(Pattern {
+                re: Regex::new(&re)
+                    .map_err(|e| format_err!("ignore pattern is invalid: {}: {}", pattern, e))?,
+                negate,
+                dir_only,
+            });
+        }
+
+        Ok(ret)
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+
+        let path: Vec<String> = path
+            .components()
+            .filter_map(|x| match x {
+                Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        let path = path.join("/");
+
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if (is_dir || !pattern.dir_only) && pattern.re.is_match(&path) {
+                ignored = !pattern.negate;
+            }
+        }
+
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignore(patterns: &[&str]) -> Ignore {
+        let patterns: Vec<String> = patterns.iter().map(|x| x.to_string()).collect();
+        Ignore::new(&patterns, true).unwrap()
+    }
+
+    fn file(ignore: &Ignore, path: &str) -> bool {
+        ignore.is_ignored(Path::new(path), false)
+    }
+
+    fn dir(ignore: &Ignore, path: &str) -> bool {
+        ignore.is_ignored(Path::new(path), true)
+    }
+
+    #[test]
+    fn names_match_at_any_depth() {
+        let ignore = ignore(&["*.tmp", "Thumbs.db"]);
+
+        assert!(file(&ignore, "a.tmp"));
+        assert!(file(&ignore, "x/y/a.tmp"));
+        assert!(file(&ignore, "x/Thumbs.db"));
+        assert!(!file(&ignore, "a.tmp.txt"));
+        assert!(!file(&ignore, "Thumbs.db.bak"));
+    }
+
+    #[test]
+    fn paths_are_anchored() {
+        let ignore = ignore(&["build/out", "/cache"]);
+
+        assert!(dir(&ignore, "build/out"));
+        assert!(!dir(&ignore, "x/build/out"));
+        assert!(dir(&ignore, "cache"));
+        assert!(!dir(&ignore, "x/cache"));
+    }
+
+    #[test]
+    fn wildcards() {
+        let ignore = ignore(&["a?.log", "[0-9]*.bak", "[!x]y", "logs/**/*.gz", "**/tmp"]);
+
+        assert!(file(&ignore, "ab.log"));
+        assert!(!file(&ignore, "abc.log"));
+        assert!(file(&ignore, "1.bak"));
+        assert!(!file(&ignore, "a1.bak"));
+        assert!(file(&ignore, "ay"));
+        assert!(!file(&ignore, "xy"));
+        assert!(file(&ignore, "logs/a.gz"));
+        assert!(file(&ignore, "logs/2024/03/a.gz"));
+        assert!(!file(&ignore, "old/logs/a.gz"));
+        assert!(dir(&ignore, "tmp"));
+        assert!(dir(&ignore, "a/b/tmp"));
+    }
+
+    #[test]
+    fn stars_stay_in_one_directory() {
+        let ignore = ignore(&["docs/*.pdf"]);
+
+        assert!(file(&ignore, "docs/a.pdf"));
+        assert!(!file(&ignore, "docs/x/a.pdf"));
+    }
+
+    #[test]
+    fn directories_only() {
+        let ignore = ignore(&["target/"]);
+
+        assert!(dir(&ignore, "target"));
+        assert!(dir(&ignore, "x/target"));
+        assert!(!file(&ignore, "target"));
+    }
+
+    #[test]
+    fn last_match_wins() {
+        let ignore = ignore(&["*.log", "!keep.log", "keep.log/"]);
+
+        assert!(file(&ignore, "a.log"));
+        assert!(!file(&ignore, "keep.log"));
+        assert!(dir(&ignore, "keep.log"));
+    }
+
+    #[test]
+    fn comments_blanks_and_literals() {
+        let ignore = ignore(&["# *.txt", "", "   ", "a+b(1).txt", "[unclosed"]);
+
+        assert!(!file(&ignore, "a.txt"));
+        assert!(file(&ignore, "a+b(1).txt"));
+        assert!(!file(&ignore, "aab1.txt"));
+        assert!(file(&ignore, "[unclosed"));
+    }
+
+    #[test]
+    fn case_sensitivity() {
+        let patterns = vec!["*.TMP".to_owned()];
+
+        let sensitive = Ignore::new(&patterns, true).unwrap();
+        assert!(file(&sensitive, "a.TMP"));
+        assert!(!file(&sensitive, "a.tmp"));
+
+        let insensitive = Ignore::new(&patterns, false).unwrap();
+        assert!(file(&insensitive, "a.tmp"));
+    }
+
+    #[test]
+    fn nothing_is_ignored_by_default() {
+        assert!(!file(&Ignore::default(), "a.tmp"));
+    }
+}
@@ -0,0 +1,204 @@
+use failure::Error;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+const JOURNAL_FILE: &str = ".ubackup-journal";
+
+/// One line of the journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// The snapshot the interrupted run was writing to.
+    Snapshot(PathBuf),
+    /// A file copied or linked, with the size and mtime of its source.
+    Done {
+        dest: String,
+        size: u64,
+        mtime: (u64, u32),
+    },
+}
+
+fn source_stamp(src: &Path) -> Option<(u64, (u64, u32))> {
+    let md = src.metadata().ok()?;
+    let mtime = md.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((md.len(), (mtime.as_secs(), mtime.subsec_nanos())))
+}
+
+/// The files an interrupted run completed on a destination.
+///
+/// Each completed file is appended to a journal at the destination root as
+/// soon as it is written, and the journal is removed once the run finishes.
+/// A run that finds a journal skips the files it lists whose source hasn't
+/// changed since, and carries on with the snapshot it names.
+pub(crate) struct Journal {
+    root: PathBuf,
+    snapshot: Option<PathBuf>,
+    done: BTreeMap<String, (u64, (u64, u32))>,
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    /// Reads the journal left under `root`, if any.
+    pub fn load(root: &Path) -> Journal {
+        let mut journal = Journal {
+            root: root.to_owned(),
+            snapshot: None,
+            done: BTreeMap::new(),
+            file: Mutex::new(None),
+        };
+
+        if let Ok(file) = File::open(root.join(JOURNAL_FILE)) {
+            // A line cut short by the interruption is ignored.
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                match serde_json::from_str(&line) {
+                    Ok(Record::Snapshot(path)) => journal.snapshot = Some(path),
+                    Ok(Record::Done { dest, size, mtime }) => {
+                        journal.done.insert(dest, (size, mtime));
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+
+        journal
+    }
+
+    /// The snapshot the interrupted run was writing to, if it still exists.
+    pub fn snapshot(&self) -> Option<PathBuf> {
+        self.snapshot.clone().filter(|path| path.is_dir())
+    }
+
+    fn key(&self, dest: &Path) -> String {
+        dest.strip_prefix(&self.root)
+            .unwrap_or(dest)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Whether the interrupted run completed `dest`, and `src` hasn't changed
+    /// since.
+    pub fn is_done(&self, src: &Path, dest: &Path) -> bool {
+        match self.done.get(&self.key(dest)) {
+            Some(stamp) => dest.is_file() && source_stamp(src).as_ref() == Some(stamp),
+            None => false,
+        }
+    }
+
+    fn append(&self, record: &Record) -> Result<(), Error> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        match *self.file.lock().unwrap() {
+            Some(ref mut file) => Ok(file.write_all(line.as_bytes())?),
+            None => Err(format_err!("journal is not open")),
+        }
+    }
+
+    /// Starts journaling a run writing to `snapshot`. The journal is created
+    /// before anything is written, so that any interruption leaves it behind.
+    pub fn begin(&self, snapshot: Option<&Path>) -> Result<(), Error> {
+        fs::create_dir_all(&self.root)?;
+        *self.file.lock().unwrap() = Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.root.join(JOURNAL_FILE))?,
+        );
+
+        match snapshot {
+            Some(snapshot) => self.append(&Record::Snapshot(snapshot.to_owned())),
+            None => Ok(()),
+        }
+    }
+
+    /// Records that `src` has been copied or linked to `dest`.
+    pub fn done(&self, src: &Path, dest: &Path) -> Result<(), Error> {
+        match source_stamp(src) {
+            Some((size, mtime)) => self.append(&Record::Done {
+                dest: self.key(dest),
+                size,
+                mtime,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the journal once the run has finished.
+    pub fn finish(self) -> Result<(), Error> {
+        drop(self.file);
+        match fs::remove_file(self.root.join(JOURNAL_FILE)) {
+            Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(format_err!("unable to remove journal: {}", e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A run on `out` that copied `a` but was interrupted before `b`.
+    fn interrupted(test: &str) -> TempDir {
+        let dir = TempDir::new(test);
+        let (root, snapshot) = (dir.join("out"), dir.join("out/snapshot"));
+        let src = dir.write("src/a", "a");
+        dir.write("src/b", "b");
+
+        let journal = Journal::load(&root);
+        journal.begin(Some(&snapshot)).unwrap();
+        let dest = dir.write("out/snapshot/a", "a");
+        journal.done(&src, &dest).unwrap();
+        dir
+    }
+
+    #[test]
+    fn interrupted_runs_resume() {
+        let dir = interrupted("journal-resume");
+        let journal = Journal::load(&dir.join("out"));
+
+        assert_eq!(journal.snapshot(), Some(dir.join("out/snapshot")));
+        assert!(journal.is_done(&dir.join("src/a"), &dir.join("out/snapshot/a")));
+        assert!(!journal.is_done(&dir.join("src/b"), &dir.join("out/snapshot/b")));
+    }
+
+    #[test]
+    fn changed_sources_are_copied_again() {
+        let dir = interrupted("journal-changed");
+        dir.write("src/a", "changed");
+        let journal = Journal::load(&dir.join("out"));
+
+        assert!(!journal.is_done(&dir.join("src/a"), &dir.join("out/snapshot/a")));
+    }
+
+    #[test]
+    fn cut_short_lines_are_ignored() {
+        let dir = interrupted("journal-cut-short");
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join("out").join(JOURNAL_FILE))
+            .unwrap();
+        file.write_all(b"{\"done\":{\"dest\":\"b\",\"si").unwrap();
+        let journal = Journal::load(&dir.join("out"));
+
+        assert!(journal.is_done(&dir.join("src/a"), &dir.join("out/snapshot/a")));
+        assert!(!journal.is_done(&dir.join("src/b"), &dir.join("out/snapshot/b")));
+    }
+
+    #[test]
+    fn finished_runs_leave_no_journal() {
+        let dir = interrupted("journal-finish");
+        Journal::load(&dir.join("out")).finish().unwrap();
+        let journal = Journal::load(&dir.join("out"));
+
+        assert!(!dir.join("out").join(JOURNAL_FILE).exists());
+        assert_eq!(journal.snapshot(), None);
+        assert!(!journal.is_done(&dir.join("src/a"), &dir.join("out/snapshot/a")));
+    }
+}
@@ -1,10 +1,11 @@
 #[allow(unused_imports)]
 #[macro_use]
 extern crate failure;
+extern crate fastcdc;
 use failure::Error;
 
 extern crate systemstat;
-use systemstat::{Platform, System};
+use systemstat::ByteSize;
 
 extern crate config;
 extern crate serde;
@@ -16,96 +17,335 @@ extern crate itertools;
 
 use itertools::{Either, Itertools};
 
+extern crate argon2;
+extern crate blake3;
+extern crate chacha20poly1305;
+extern crate chrono;
+extern crate data_encoding;
+extern crate getrandom;
+extern crate hex;
 extern crate hostname;
 extern crate regex;
+extern crate serde_yaml;
+extern crate sha2;
+extern crate tar;
+#[cfg(unix)]
+extern crate xattr;
+extern crate zip;
+extern crate zstd;
 
+mod archive;
+mod atomic;
+mod compare;
+mod crypto;
+mod drive;
+mod ignore;
+mod journal;
+mod manifest;
+mod metadata;
+mod mirror;
+mod observer;
+mod plan;
+mod pool;
+mod report;
+mod repository;
+mod restore;
 mod settings;
-pub use settings::{AppConfig, Settings};
+mod snapshot;
+mod template;
+#[cfg(test)]
+mod testing;
+mod verify;
+pub use archive::{extract, list, ArchiveEntry};
+pub use crypto::EncryptionHeader;
+pub use manifest::{scrub, Damage, ScrubReport, ScrubbedFile};
+pub use observer::BackupObserver;
+pub use plan::{apply, Action, BackupPlan, Crypt, DestinationPlan, PlannedFile};
+pub use report::{BackupReport, DestinationReport, FileReport, Outcome};
+pub use restore::{restore, RestoreOptions};
+pub use settings::{
+    AppConfig, Archive, Compare, DestDrive, Destinations, Encryption, Filters, Match, Mirror,
+    Preserve, Role, Settings, Snapshots, SpaceCheck, SrcFile,
+};
+pub use verify::{verify, Mismatch, VerifiedFile, VerifyOptions, VerifyReport};
 
-use std::collections::VecDeque;
+use chrono::{DateTime, Local};
+use compare::Comparator;
+use crypto::{Cipher, Names};
+use drive::get_drive;
+use ignore::Ignore;
+use journal::Journal;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::OsStr;
 use std::fs;
 use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use template::Template;
 
-fn get_current_drive() -> Option<String> {
-    match std::env::current_dir() {
-        Ok(path) => {
-            let mut components = path.components();
-            let mut path = PathBuf::new();
-
-            while !path.ends_with(std::path::MAIN_SEPARATOR.to_string()) {
-                match components.next() {
-                    Some(component) => match component {
-                        Component::RootDir => path.push(std::path::MAIN_SEPARATOR.to_string()),
-                        Component::Prefix(prefix) => path.push(prefix.as_os_str()),
-                        _ => {}
-                    },
-                    None => return None,
+/// Files and directories ubackup keeps for itself in the destination.
+const RESERVED_PREFIX: &str = ".ubackup-";
+
+fn build_initial_dest<'a>(drive: &'a str, format: &'a str) -> Result<PathBuf, Error> {
+    let mut dest = PathBuf::new();
+    dest.push(drive);
+
+    let now = Local::now();
+    for part in template::parse_path(format, &[])
+        .map_err(|e| format_err!("dest.format is invalid: {}: {}", format, e))?
+    {
+        let part = part.expand(&[], &now)?;
+        if !part.as_os_str().is_empty() {
+            dest.push(part);
+        }
+    }
+
+    Ok(dest)
+}
+
+/// Splits a `{name:pattern}` component of `from` into its name and pattern.
+fn named_capture(path: &str) -> Option<(&str, &str)> {
+    let inner = path.strip_prefix('{')?.strip_suffix('}')?;
+    let (name, pattern) = inner.split_at(inner.find(':')?);
+
+    if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        Some((name, &pattern[1..]))
+    } else {
+        None
+    }
+}
+
+/// The pattern of a `from` component, without any `{name:...}` around it.
+pub(crate) fn capture_pattern(path: &str) -> &str {
+    named_capture(path).map(|x| x.1).unwrap_or(path)
+}
+
+/// Whether a `from` component produces a capture.
+pub(crate) fn is_capture(path: &str) -> bool {
+    is_wildcard(capture_pattern(path)) || brace_filter(path).is_some()
+}
+
+/// One item per capture in `from`, with its name if it has one.
+pub(crate) fn capture_names(from: &str) -> Vec<Option<String>> {
+    Path::new(from)
+        .components()
+        .filter_map(|x| match x {
+            Component::Normal(part) => {
+                let part = part.to_string_lossy();
+                if is_capture(&part) {
+                    Some(named_capture(&part).map(|x| x.0.to_owned()))
+                } else {
+                    None
                 }
             }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The filters of `entry` in the order `glob` consumes them: one per capture
+/// group, with `{...}` components providing their own.
+pub(crate) fn filter_queue(entry: &SrcFile) -> Result<VecDeque<Match>, Error> {
+    let named = match entry.filters {
+        Filters::Positional(ref filters) => return Ok(filters.clone().into()),
+        Filters::Named(ref named) => named,
+    };
+
+    let mut queue = VecDeque::new();
+    for component in Path::new(&entry.from).components() {
+        if let Component::Normal(part) = component {
+            let part = part.to_string_lossy();
+            let pattern = capture_pattern(&part);
+            if brace_filter(&part).is_some() || !is_wildcard(pattern) {
+                continue;
+            }
+
+            let filter = named_capture(&part)
+                .and_then(|(name, _)| named.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)))
+                .map(|(_, filter)| filter.clone())
+                .unwrap_or_default();
+
+            let groups = if pattern == "**" {
+                1
+            } else {
+                component_regex(pattern, entry.is_case_sensitive())?.captures_len() - 1
+            };
 
-            return Some(path.to_string_lossy().into_owned());
+            for _ in 0..groups {
+                queue.push_back(filter.clone());
+            }
         }
-        _ => {}
     }
-    return None;
+
+    Ok(queue)
+}
+
+/// Whether a `from` component has to be matched against directory entries.
+fn is_wildcard(path: &str) -> bool {
+    path.starts_with("re:") || path.contains(['*', '?', '['])
+}
+
+/// The directories of `from` above its first wildcard component: where an
+/// entry's sources are found.
+pub(crate) fn glob_base(from: &str) -> PathBuf {
+    Path::new(from)
+        .components()
+        .take_while(|x| match x {
+            Component::Normal(name) => !is_wildcard(&name.to_string_lossy()),
+            _ => true,
+        })
+        .collect()
 }
 
-fn get_drive_by_label(label: &str) -> Option<String> {
-    let sys = System::new();
-    match sys.mounts() {
-        Ok(mounts) => {
-            for mount in mounts {
-                if mount.fs_mounted_from == label {
-                    return Some(mount.fs_mounted_on);
+/// Compiles a `from` component into a regex.
+///
+/// A `re:` prefix takes the rest of the component as a regex. Otherwise each
+/// `*` becomes a capture group, `?` matches one character and `[...]` a
+/// character class (`[!...]` negated). Filters apply to the capture groups in
+/// order.
+fn component_regex(path: &str, case_sensitive: bool) -> Result<regex::Regex, Error> {
+    let body = match path.strip_prefix("re:") {
+        Some(re) => re.to_owned(),
+        None => {
+            let mut body = String::new();
+            let mut chars = path.chars();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '*' => body.push_str("(.*)"),
+                    '?' => body.push('.'),
+                    '[' => {
+                        let class: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                        match class.strip_prefix('!') {
+                            Some(class) => body.push_str(&format!("[^{}]", class)),
+                            None => body.push_str(&format!("[{}]", class)),
+                        }
+                    }
+                    c => body.push_str(&regex::escape(&c.to_string())),
                 }
             }
-            return None;
+
+            body
         }
-        Err(_) => return None,
-    }
+    };
+
+    regex::Regex::new(&format!(
+        "{}^(?:{})$",
+        if case_sensitive { "" } else { "(?i)" },
+        body
+    ))
+    .map_err(|e| format_err!("from is invalid: {}: {}", path, e))
 }
 
-fn get_drive(label: &str) -> Result<String, Error> {
-    if label == "$CURRENTDRIVE" {
-        match get_current_drive() {
-            Some(path) => return Ok(path),
-            None => return Err(format_err!("unable to detect current drive")),
+/// Parses a `{a,b,-c}` component into the filter it stands for.
+fn brace_filter(path: &str) -> Option<Match> {
+    if !(path.starts_with('{') && path.ends_with('}')) || named_capture(path).is_some() {
+        return None;
+    }
+
+    let mut exclude: Vec<String> = vec![];
+    let mut only: Vec<String> = vec![];
+
+    for item in path[1..path.len() - 1]
+        .split(',')
+        .map(|x| x.trim().to_lowercase())
+    {
+        if let Some(item) = item.strip_prefix('-') {
+            exclude.push(item.to_owned());
+        } else {
+            only.push(item);
         }
-    } else {
-        match get_drive_by_label(&label) {
-            Some(path) => return Ok(path),
-            None => return Err(format_err!("unable to find drive with label: {}", label)),
+    }
+
+    Some(Match { exclude, only })
+}
+
+fn passes_filter(mat: &str, filter: &Match) -> bool {
+    let mat = mat.to_lowercase();
+    !filter.exclude.contains(&mat) && (filter.only.is_empty() || filter.only.contains(&mat))
+}
+
+/// Checks `name` against a wildcard component, applying one filter per
+/// capture group.
+fn passes_filters(re: &regex::Regex, name: &str, filters: &VecDeque<Match>) -> bool {
+    match re.captures(name) {
+        Some(cap) => {
+            let mut filters = filters.clone();
+
+            cap.iter()
+                .skip(1)
+                .flatten()
+                .all(|mat| passes_filter(mat.as_str(), &filters.pop_front().unwrap_or_default()))
         }
+        None => false,
     }
 }
 
-fn build_initial_dest<'a>(drive: &'a str, format: &'a str) -> Result<PathBuf, Error> {
-    let mut dest = PathBuf::new();
-    dest.push(drive);
+/// Checks every directory in a `**` capture against its filter.
+fn passes_recursive_filter(capture: &str, filter: &Match) -> bool {
+    Path::new(capture).components().all(|x| match x {
+        Component::Normal(name) => passes_filter(&name.to_string_lossy(), filter),
+        _ => true,
+    })
+}
 
-    let components: Vec<Component> = Path::new(format).components().collect();
-    for item in components {
-        match item {
-            Component::RootDir => {}
-            Component::Normal(path) => {
-                if path == "$HOSTNAME" {
-                    dest.push(&hostname::get_hostname().unwrap_or_default());
-                } else {
-                    dest.push(path);
+/// Expands a `**` component: `current_path` itself and every directory below
+/// it, each captured as its path relative to `current_path`, joined with `/`
+/// on every platform.
+fn glob_recursive(
+    current_path: &Path,
+    parts_remaining: &VecDeque<Component>,
+    filters: &mut VecDeque<Match>,
+    matches: &VecDeque<String>,
+    case_sensitive: bool,
+) -> Result<GlobResults, Error> {
+    let filter = filters.pop_front().unwrap_or_default();
+    let mut results: GlobResults = vec![];
+    let mut dirs = vec![(current_path.to_owned(), String::new())];
+
+    while let Some((dir, rel)) = dirs.pop() {
+        let mut new_matches = matches.clone();
+        new_matches.push_back(rel.clone());
+
+        match glob(
+            &mut dir.clone(),
+            &mut parts_remaining.clone(),
+            &mut filters.clone(),
+            &mut new_matches,
+            case_sensitive,
+        ) {
+            Ok(v) => results.extend(v),
+            Err(e) => results.push(Err(e)),
+        }
+
+        // A trailing `**` would only repeat what copying the directory does.
+        if parts_remaining.is_empty() {
+            break;
+        }
+
+        match fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    match entry {
+                        Ok(entry) => {
+                            let is_dir = entry.file_type().map(|x| x.is_dir()).unwrap_or(false);
+                            let name = entry.file_name().to_string_lossy().into_owned();
+                            if is_dir && passes_filter(&name, &filter) {
+                                let rel = match rel.as_str() {
+                                    "" => name,
+                                    rel => format!("{}/{}", rel, name),
+                                };
+                                dirs.push((entry.path(), rel));
+                            }
+                        }
+                        Err(e) => results.push(Err(e.into())),
+                    }
                 }
             }
-            _ => return Err(format_err!("dest.format is invalid: {}", format)),
+            Err(e) => results.push(Err(format_err!("{}: {}", dir.to_string_lossy(), e))),
         }
     }
 
-    Ok(dest)
-}
-
-fn count(s: &str, c: char) -> usize {
-    let mut s = s.to_owned();
-    s.retain(|x| x == c);
-    s.len()
+    Ok(results)
 }
 
 #[derive(Debug)]
@@ -114,13 +354,16 @@ struct GlobMatch {
     matches: Vec<String>,
 }
 
+type GlobResults = Vec<Result<GlobMatch, Error>>;
+
 fn glob(
     current_path: &mut PathBuf,
     parts_remaining: &mut VecDeque<Component>,
     filters: &mut VecDeque<settings::Match>,
     matches: &mut VecDeque<String>,
-) -> Result<Vec<Result<GlobMatch, Error>>, Error> {
-    if parts_remaining.len() == 0 {
+    case_sensitive: bool,
+) -> Result<GlobResults, Error> {
+    if parts_remaining.is_empty() {
         return Ok(vec![Ok(GlobMatch {
             path: current_path.to_owned(),
             matches: matches.to_owned().into(),
@@ -130,73 +373,44 @@ fn glob(
     match parts_remaining.pop_front().unwrap() {
         Component::Prefix(prefix) => current_path.push(prefix.as_os_str()),
         Component::RootDir => current_path.push(std::path::MAIN_SEPARATOR.to_string()),
-        Component::Normal(path) => {
-            let path: String = path.to_string_lossy().into_owned();
-            if path.contains("*") {
-                let filter_count = count(&path, '*');
+        Component::Normal(os_path) => {
+            let path: String = os_path.to_string_lossy().into_owned();
+            if let Some((_, pattern)) = os_path.to_str().and_then(named_capture) {
+                parts_remaining.push_front(Component::Normal(OsStr::new(pattern)));
+            } else if path == "**" {
+                return glob_recursive(
+                    current_path,
+                    parts_remaining,
+                    filters,
+                    matches,
+                    case_sensitive,
+                );
+            } else if is_wildcard(&path) {
+                let re = component_regex(&path, case_sensitive)?;
+                let filter_count = re.captures_len() - 1;
                 let filters = filters.clone();
 
-                let re = regex::Regex::new(&format!(
-                    r"(?i)^{}$",
-                    regex::escape(&path).replace("\\*", "(.*)")
-                ))
-                .unwrap();
-
                 match fs::read_dir(&current_path) {
                     Ok(v) => {
-                        let (successes, failures): (Vec<_>, Vec<Result<GlobMatch, Error>>) = v
-                            .partition_map(|x| match x {
+                        let (successes, failures): (Vec<_>, GlobResults) =
+                            v.partition_map(|x| match x {
                                 Ok(v) => Either::Left(v),
                                 Err(v) => Either::Right(Err(v.into())),
                             });
 
-                        let (successes, more_failures): (
-                            Vec<Vec<Result<GlobMatch, Error>>>,
-                            Vec<Result<GlobMatch, Error>>,
-                        ) = successes
+                        let (successes, more_failures): (Vec<GlobResults>, GlobResults) = successes
                             .into_iter()
                             .filter(|x| {
                                 let x: &Path = &x.path();
 
-                                if x.is_file() && parts_remaining.len() > 0 {
+                                if x.is_file() && !parts_remaining.is_empty() {
                                     return false;
                                 }
 
-                                let file_name: String = x
-                                    .file_name()
-                                    .unwrap()
-                                    .to_string_lossy()
-                                    .into_owned()
-                                    .to_lowercase();
-
-                                match re.captures(&file_name) {
-                                    Some(cap) => {
-                                        let mut filters = filters.clone();
-
-                                        for (i, mat) in cap.iter().enumerate() {
-                                            if i > 0 {
-                                                if let Some(mat) = mat {
-                                                    let mat = mat.as_str().to_owned();
-                                                    let filter =
-                                                        filters.pop_front().unwrap_or_default();
-
-                                                    if filter.exclude.contains(&mat) {
-                                                        return false;
-                                                    }
-
-                                                    if filter.only.len() > 0
-                                                        && !filter.only.contains(&mat)
-                                                    {
-                                                        return false;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    None => return false,
-                                }
+                                let file_name: String =
+                                    x.file_name().unwrap().to_string_lossy().into_owned();
 
-                                true
+                                passes_filters(&re, &file_name, &filters)
                             })
                             .map(|x| {
                                 let mut new_matches = matches.clone();
@@ -214,14 +428,14 @@ fn glob(
                                     &mut parts_remaining.clone(),
                                     &mut filters,
                                     &mut new_matches,
+                                    case_sensitive,
                                 )
                             })
                             .partition_map(|x| match x {
                                 Ok(v) => Either::Left(v),
                                 Err(v) => Either::Right(Err(v)),
                             });
-                        let successes: Vec<Result<GlobMatch, Error>> =
-                            successes.into_iter().flatten().collect();
+                        let successes: GlobResults = successes.into_iter().flatten().collect();
 
                         return Ok(vec![successes, failures, more_failures]
                             .into_iter()
@@ -230,24 +444,8 @@ fn glob(
                     }
                     Err(v) => return Err(format_err!("{}: {}", current_path.to_string_lossy(), v)),
                 }
-            } else if path.starts_with('{') && path.ends_with('}') {
-                let filter: Vec<String> = path[1..path.len() - 1]
-                    .split(',')
-                    .map(|x| x.trim().to_lowercase().to_owned())
-                    .collect();
-
-                let mut exclude: Vec<String> = vec![];
-                let mut only: Vec<String> = vec![];
-
-                for item in filter {
-                    if item.starts_with('-') {
-                        exclude.push(item[1..].to_owned());
-                    } else {
-                        only.push(item);
-                    }
-                }
-
-                filters.push_front(settings::Match { exclude, only });
+            } else if let Some(filter) = brace_filter(&path) {
+                filters.push_front(filter);
                 parts_remaining.push_front(Component::Normal(OsStr::new("*")));
             } else {
                 current_path.push(path);
@@ -260,146 +458,892 @@ fn glob(
         return Ok(vec![]);
     }
 
-    glob(current_path, parts_remaining, filters, matches)
+    glob(
+        current_path,
+        parts_remaining,
+        filters,
+        matches,
+        case_sensitive,
+    )
 }
 
-fn path_from_matches(format: VecDeque<Component>, matches: Vec<String>) -> Result<PathBuf, Error> {
-    let mut format = format.clone();
+fn glob_entry(entry: &SrcFile) -> Result<GlobResults, Error> {
+    glob(
+        &mut PathBuf::new(),
+        &mut Path::new(&entry.from).components().collect(),
+        &mut filter_queue(entry)?,
+        &mut VecDeque::new(),
+        entry.is_case_sensitive(),
+    )
+}
+
+fn path_from_matches(
+    to: &[Template],
+    matches: &[String],
+    now: &DateTime<Local>,
+) -> Result<PathBuf, Error> {
     let mut ret = PathBuf::new();
 
-    while !format.is_empty() {
-        match format.pop_front().unwrap() {
-            Component::RootDir => {}
-            Component::Normal(path) => {
-                let path: String = path.to_string_lossy().into_owned();
-                if path.starts_with('$') {
-                    let i = path[1..].parse::<usize>()?;
+    for part in to {
+        let part = part.expand(matches, now)?;
+        if !part.as_os_str().is_empty() {
+            ret.push(part);
+        }
+    }
 
-                    match i {
-                        _ => ret.push(&matches[i - 1]),
-                    }
-                } else {
-                    ret.push(path);
-                }
+    Ok(ret)
+}
+
+struct Run<'a> {
+    observer: &'a mut dyn BackupObserver,
+    comparator: Comparator,
+    journal: Option<Journal>,
+    names: Names,
+    jobs: usize,
+    snapshot: Option<(PathBuf, PathBuf)>,
+    force: bool,
+    ignore: Ignore,
+    base: PathBuf,
+    files: Vec<PlannedFile>,
+}
+
+impl<'a> Run<'a> {
+    /// Maps a path in the current snapshot to the same path in the previous one.
+    fn previous(&self, dest: &Path) -> Option<PathBuf> {
+        match self.snapshot {
+            Some((ref current, ref previous)) => {
+                dest.strip_prefix(current).ok().map(|x| previous.join(x))
             }
-            _ => return Err(format_err!("path is invalid")),
+            None => None,
         }
     }
 
-    Ok(ret)
+    /// Checks a path under the directory the current glob matched against the
+    /// ignore patterns.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        is_ignored(&self.ignore, &self.base, path, is_dir)
+    }
+
+    fn record(&mut self, file: PlannedFile) {
+        self.files.push(file);
+    }
 }
 
-fn rcopy(src: PathBuf, dest: PathBuf, config: &AppConfig, ret: &mut [u32; 4]) -> Result<(), Error> {
-    let mut internal_copy = |src: PathBuf, dest: PathBuf| {
-        if !config.dryrun {
-            match fs::copy(&src, &dest) {
-                Ok(_) => {
-                    ret[0] += 1;
-                    ret[2] += 1;
+/// Checks `path` under `base`, the directory a glob matched, against the
+/// ignore patterns.
+fn is_ignored(ignore: &Ignore, base: &Path, path: &Path, is_dir: bool) -> bool {
+    match path.strip_prefix(base) {
+        Ok(rel) => ignore.is_ignored(rel, is_dir),
+        Err(_) => false,
+    }
+}
 
-                    if !config.quiet {
-                        println!("{}: Copied.", src.to_string_lossy());
-                    }
-                }
-                Err(e) => {
-                    ret[1] += 1;
-                    eprintln!("{}: {}", src.to_string_lossy(), e);
-                }
+/// An entry of a directory read by `collect_files`, with where it goes.
+enum Found {
+    File(PathBuf, PathBuf),
+    Dir(PathBuf, PathBuf),
+    Failed(PathBuf, Option<PathBuf>, Error),
+}
+
+/// Lists the entries of the directory `src` that aren't ignored.
+fn list_dir(
+    src: &Path,
+    dest: &Path,
+    ignore: &Ignore,
+    base: &Path,
+    names: &Names,
+) -> Result<Vec<Found>, Error> {
+    let mut ret = vec![];
+
+    for file in fs::read_dir(src)? {
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                ret.push(Found::Failed(
+                    src.to_owned(),
+                    Some(dest.to_owned()),
+                    e.into(),
+                ));
+                continue;
             }
+        };
+
+        let is_dir = match file.file_type() {
+            Ok(file_type) if file_type.is_symlink() => file.path().is_dir(),
+            Ok(file_type) => file_type.is_dir(),
+            Err(_) => false,
+        };
+        let file = file.path();
+        if is_ignored(ignore, base, &file, is_dir) {
+            continue;
+        }
+
+        let mut dest = dest.to_owned();
+        match names.dest_name(file.file_name().unwrap()) {
+            Ok(name) => dest.push(name),
+            Err(e) => {
+                ret.push(Found::Failed(file, Some(dest), e));
+                continue;
+            }
+        }
+
+        ret.push(if is_dir {
+            Found::Dir(file, dest)
         } else {
-            ret[0] += 1;
-            ret[2] += 1;
+            Found::File(file, dest)
+        });
+    }
+
+    Ok(ret)
+}
+
+type Listed = HashMap<PathBuf, Result<Vec<Found>, Error>>;
 
-            if !config.quiet {
-                println!("{}: Would be copied.", src.to_string_lossy());
+/// Adds the files of directories read by `collect_files` to `files` depth
+/// first, in the order they were read, and records the entries that failed.
+fn gather(
+    found: Vec<Found>,
+    listed: &mut Listed,
+    entry: &SrcFile,
+    run: &mut Run,
+    files: &mut Vec<(PathBuf, PathBuf, Option<PathBuf>)>,
+) {
+    for found in found {
+        match found {
+            Found::File(src, dest) => {
+                let previous = run.previous(&dest);
+                files.push((src, dest, previous));
             }
+            Found::Dir(src, dest) => match listed.remove(&src) {
+                Some(Ok(found)) => gather(found, listed, entry, run, files),
+                Some(Err(e)) => run.record(PlannedFile::failed(src, Some(dest), entry, e)),
+                None => {}
+            },
+            Found::Failed(src, dest, e) => run.record(PlannedFile::failed(src, dest, entry, e)),
         }
-    };
+    }
+}
 
-    let src_md = src.metadata()?;
-    if src_md.is_file() {
-        if dest.exists() {
-            let dest_md = dest.metadata()?;
-
-            if dest_md.modified()? >= src_md.modified()? {
-                ret[0] += 1;
-                ret[3] += 1;
-                if !config.quiet {
-                    if !config.dryrun {
-                        println!("{}: Skipped.", src.to_string_lossy());
-                    } else {
-                        println!("{}: Would be skipped.", src.to_string_lossy());
+/// Lists the files under `src` that aren't ignored, with where they go and
+/// their copy in the previous snapshot. Directories are read a level at a
+/// time on up to `jobs` threads.
+fn collect_files(
+    src: PathBuf,
+    dest: PathBuf,
+    is_dir: bool,
+    entry: &SrcFile,
+    run: &mut Run,
+    files: &mut Vec<(PathBuf, PathBuf, Option<PathBuf>)>,
+) -> Result<(), Error> {
+    if !is_dir {
+        let previous = run.previous(&dest);
+        files.push((src, dest, previous));
+        return Ok(());
+    }
+
+    let mut listed = Listed::new();
+    let mut level = vec![(src.clone(), dest)];
+    while !level.is_empty() {
+        let mut next = vec![];
+        let (ignore, base, names) = (&run.ignore, &run.base, &run.names);
+
+        pool::for_each_ordered(
+            &level,
+            run.jobs,
+            |(src, dest)| list_dir(src, dest, ignore, base, names),
+            |(src, _), result| {
+                if let Ok(ref found) = result {
+                    for found in found {
+                        if let Found::Dir(src, dest) = found {
+                            next.push((src.clone(), dest.clone()));
+                        }
                     }
                 }
+                listed.insert(src.clone(), result);
+            },
+        );
+        level = next;
+    }
+
+    let found = listed.remove(&src).unwrap()?;
+    gather(found, &mut listed, entry, run, files);
+    Ok(())
+}
+
+/// Decides what to do with one file, and how many bytes it has.
+fn plan_file(
+    src: &Path,
+    dest: &Path,
+    previous: &Option<PathBuf>,
+    comparator: &Comparator,
+    journal: Option<&Journal>,
+    force: bool,
+) -> Result<(Action, u64), Error> {
+    let src_md = src.metadata()?;
+
+    let action = match dest.metadata() {
+        Ok(_) if !force && journal.is_some_and(|journal| journal.is_done(src, dest)) => {
+            Action::Skip
+        }
+        Ok(dest_md) => {
+            if !force && comparator.is_up_to_date(src, &src_md, dest, &dest_md)? {
+                Action::Skip
             } else {
-                internal_copy(src, dest);
-            }
-        } else {
-            if !config.dryrun {
-                fs::create_dir_all(dest.parent().unwrap())?;
+                Action::Copy
             }
-            internal_copy(src, dest);
         }
-    } else {
-        for entry in fs::read_dir(&src)? {
-            match entry {
-                Ok(entry) => {
-                    let entry = entry.path();
-                    let mut dest = dest.clone();
-                    dest.push(entry.file_name().unwrap());
-
-                    if let Err(e) = rcopy(entry.clone(), dest, &config, ret) {
-                        ret[1] += 1;
-                        eprintln!("{}: {}", entry.to_string_lossy(), e);
+        Err(_) => match previous {
+            Some(previous)
+                if previous.is_file()
+                    && comparator.is_up_to_date(
+                        src,
+                        &src_md,
+                        previous,
+                        &previous.metadata()?,
+                    )? =>
+            {
+                Action::Link {
+                    previous: previous.clone(),
+                }
+            }
+            _ => Action::Copy,
+        },
+    };
+
+    Ok((action, src_md.len()))
+}
+
+/// Plans copying `src` to `dest`, recursing into directories. Files are
+/// compared on up to `jobs` threads.
+fn plan_copy(src: PathBuf, dest: PathBuf, entry: &SrcFile, run: &mut Run) -> Result<(), Error> {
+    let is_dir = src.metadata()?.is_dir();
+    let mut files = vec![];
+    collect_files(src, dest, is_dir, entry, run, &mut files)?;
+
+    let comparator = &run.comparator;
+    let journal = run.journal.as_ref();
+    let force = run.force;
+    let mut planned = vec![];
+
+    pool::for_each_ordered(
+        &files,
+        run.jobs,
+        |(src, dest, previous)| plan_file(src, dest, previous, comparator, journal, force),
+        |(src, dest, _), result| {
+            planned.push(match result {
+                Ok((action, bytes)) => PlannedFile {
+                    action,
+                    src: src.clone(),
+                    dest: Some(dest.clone()),
+                    bytes,
+                    entry: entry.clone(),
+                },
+                Err(e) => PlannedFile::failed(src.clone(), Some(dest.clone()), entry, e),
+            })
+        },
+    );
+
+    run.files.extend(planned);
+    Ok(())
+}
+
+/// Plans copying the files `entry` matches to where its `to` maps them
+/// under `dest`. Returns how many paths the entry matched.
+fn plan_entry(
+    settings: &Settings,
+    entry: &SrcFile,
+    dest: &Path,
+    now: &DateTime<Local>,
+    run: &mut Run,
+) -> Result<usize, Error> {
+    let mut matched = 0;
+    let to = template::parse_path(&entry.to, &capture_names(&entry.from))
+        .map_err(|e| format_err!("to field is invalid: {}: {}", entry.to, e))?;
+    run.ignore = Ignore::new(
+        settings.config.ignore.iter().chain(&entry.ignore),
+        entry.is_case_sensitive(),
+    )?;
+
+    for file in glob_entry(entry)? {
+        match file {
+            Ok(file) => {
+                run.observer.glob_expanded(entry, &file.path);
+                matched += 1;
+
+                let mut dest = dest.to_owned();
+                match path_from_matches(&to, &file.matches, now)
+                    .and_then(|path| run.names.dest_path(&path))
+                {
+                    Ok(path) => dest.push(path),
+                    Err(e) => {
+                        run.record(PlannedFile::failed(file.path, None, entry, e));
+                        continue;
                     }
                 }
-                Err(e) => {
-                    ret[1] += 1;
-                    eprintln!("{}", e);
+
+                run.base = file.path.clone();
+                if let Err(e) = plan_copy(file.path.clone(), dest.clone(), entry, run) {
+                    run.record(PlannedFile::failed(file.path, Some(dest), entry, e));
                 }
             }
+            Err(e) => run.record(PlannedFile::failed(
+                PathBuf::from(&entry.from),
+                None,
+                entry,
+                e,
+            )),
         }
     }
 
-    Ok(())
+    Ok(matched)
 }
 
-pub fn backup(settings: Settings) -> Result<[u32; 4], Error> {
-    let dest: PathBuf =
-        build_initial_dest(&get_drive(&settings.dest.label)?, &settings.dest.format)?;
-
-    let mut ret: [u32; 4] = [0, 0, 0, 0];
-
-    for entry in settings.files {
-        let to: VecDeque<Component> = Path::new(&entry.to).components().collect();
-        let mut source: VecDeque<Component> = Path::new(&entry.from).components().collect();
-
-        for file in glob(
-            &mut PathBuf::new(),
-            &mut source,
-            &mut entry.filters.into(),
-            &mut VecDeque::new(),
-        )? {
-            match file {
-                Ok(file) => {
-                    let mut dest: PathBuf = dest.clone();
-                    match path_from_matches(to.clone(), file.matches) {
-                        Ok(path) => dest.push(path),
-                        _ => return Err(format_err!("to field is invalid: {}", entry.to)),
-                    }
+/// The destinations of a run: every mirror, and the first available fallback.
+/// Destinations that can't be found come with the error instead of a root.
+pub(crate) fn destinations(dests: &[DestDrive]) -> Vec<(&DestDrive, Result<PathBuf, Error>)> {
+    let root = |dest: &DestDrive| {
+        get_drive(&dest.label).and_then(|drive| build_initial_dest(&drive, &dest.format))
+    };
 
-                    if let Err(e) = rcopy(file.path.clone(), dest, &settings.config, &mut ret) {
-                        ret[1] += 1;
-                        eprintln!("{}: {}", file.path.to_string_lossy(), e);
-                    }
+    let mut ret = vec![];
+    let mut fallback = None;
+
+    for dest in dests {
+        match dest.role {
+            Role::Mirror => ret.push((dest, root(dest))),
+            Role::Fallback => {
+                if let Some((_, Ok(_))) = fallback {
+                    continue;
                 }
-                Err(e) => {
-                    ret[1] += 1;
-                    eprintln!("{}", e);
+
+                fallback = Some((
+                    dest,
+                    root(dest)
+                        .map_err(|e| format_err!("no fallback destination is available: {}", e)),
+                ));
+            }
+        }
+    }
+
+    ret.extend(fallback);
+    ret
+}
+
+/// Checks that the drive holding `root` has room for the bytes `plan` would
+/// copy. Files that would be overwritten are counted in full.
+fn preflight(plan: &DestinationPlan, root: &Path) -> Result<(), Error> {
+    let needed = plan.bytes();
+
+    match drive::free_space(root) {
+        Some(avail) if avail.as_u64() < needed => Err(format_err!(
+            "not enough free space on {}: {} needed, {} available",
+            root.to_string_lossy(),
+            ByteSize::b(needed),
+            avail
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Plans backing up every entry in `settings.files` to every destination.
+///
+/// A destination that can't be found, or lacks space when `space_check` is
+/// `abort`, is planned as an error, unless no destination can be used,
+/// which fails the whole plan.
+pub fn plan(settings: &Settings) -> Result<BackupPlan, Error> {
+    plan_with(settings, &mut ())
+}
+
+fn plan_with(settings: &Settings, observer: &mut dyn BackupObserver) -> Result<BackupPlan, Error> {
+    let mut plan = BackupPlan {
+        compare: settings.config.compare,
+        jobs: settings.config.jobs,
+        preserve: settings.config.preserve.clone(),
+        crypt: if settings.encryption.enabled {
+            Crypt::Encrypt
+        } else {
+            Crypt::Off
+        },
+        warnings: vec![],
+        destinations: vec![],
+    };
+    let mut unavailable = None;
+
+    for (dest, root) in destinations(settings.dest.as_slice()) {
+        let mut planned = DestinationPlan {
+            label: dest.label.clone(),
+            root: None,
+            error: None,
+            files: vec![],
+            prune: vec![],
+            snapshot: None,
+            journal: dest.is_tree(),
+            archive: None,
+            repository: None,
+            manifest: !dest.repository,
+            encryption: None,
+        };
+
+        match root {
+            Ok(root) => {
+                plan_destination(settings, &root, dest, &mut planned, observer)?;
+
+                if settings.config.space_check != SpaceCheck::Off {
+                    if let Err(e) = preflight(&planned, &root) {
+                        if settings.config.space_check == SpaceCheck::Abort {
+                            planned.error = Some(e.to_string());
+                            planned.files.clear();
+                            planned.prune.clear();
+                            unavailable.get_or_insert(e);
+                        } else {
+                            observer.warning(&e.to_string());
+                            plan.warnings.push(e.to_string());
+                        }
+                    }
                 }
+
+                planned.root = Some(root);
+            }
+            Err(e) => {
+                planned.error = Some(e.to_string());
+                unavailable.get_or_insert(e);
             }
         }
+
+        plan.destinations.push(planned);
     }
 
-    Ok(ret)
+    match unavailable {
+        Some(e) if plan.destinations.iter().all(|x| x.error.is_some()) => Err(e),
+        _ => Ok(plan),
+    }
+}
+
+fn plan_destination(
+    settings: &Settings,
+    root: &Path,
+    drive: &DestDrive,
+    planned: &mut DestinationPlan,
+    observer: &mut dyn BackupObserver,
+) -> Result<(), Error> {
+    let cipher = if settings.encryption.enabled {
+        let (cipher, header) = Cipher::load(root, &settings.encryption)?;
+        planned.encryption = header;
+        Some(Arc::new(cipher))
+    } else {
+        None
+    };
+
+    let now = Local::now();
+    let name = now.format(snapshot::SNAPSHOT_FORMAT).to_string();
+    let archive = drive
+        .archive
+        .extension()
+        .map(|extension| root.join(format!("{}.{}", name, extension)));
+    let repository = if drive.repository {
+        Some(repository::index_path(root, &name))
+    } else {
+        None
+    };
+    // The single file the run is written to, if not a tree of files.
+    let packed = archive.as_ref().or(repository.as_ref());
+    let snapshots = settings.snapshots.enabled && packed.is_none();
+
+    let journal = Journal::load(root);
+    let (dest, previous) = match packed {
+        Some(packed) => (packed.clone(), None),
+        None if snapshots => snapshot::begin(root, journal.snapshot()),
+        None => (root.to_owned(), None),
+    };
+
+    let trash = dest.join(mirror::TRASH_DIR).join(&name);
+
+    let mut run = Run {
+        observer,
+        comparator: Comparator::load(root, settings.config.compare).encrypted(cipher.clone()),
+        journal: packed.is_none().then_some(journal),
+        names: Names::backup(cipher),
+        jobs: settings.config.jobs,
+        snapshot: previous.map(|previous| (dest.clone(), previous)),
+        force: false,
+        ignore: Ignore::default(),
+        base: PathBuf::new(),
+        files: vec![],
+    };
+
+    for entry in &settings.files {
+        let matched = plan_entry(settings, entry, &dest, &now, &mut run)?;
+
+        if entry.mirror != Mirror::Off && !settings.snapshots.enabled && packed.is_none() {
+            mirror::mirror(entry, matched, &dest, &trash, &mut run);
+        }
+    }
+
+    if snapshots {
+        planned.prune = snapshot::expired(root, &settings.snapshots, &dest);
+        planned.snapshot = Some(dest);
+    }
+
+    if let Some(ref index) = repository {
+        repository::link_unchanged(index, &mut run.files)?;
+    }
+
+    planned.archive = archive;
+    planned.repository = repository;
+
+    planned.files = run.files;
+    Ok(())
+}
+
+/// Backs up every entry in `settings.files` to every destination: `plan`
+/// followed by `apply`. A dry run reports the plan instead of applying it.
+pub fn backup(
+    settings: Settings,
+    observer: &mut dyn BackupObserver,
+) -> Result<BackupReport, Error> {
+    let plan = plan_with(&settings, observer)?;
+    plan::execute(plan, &settings.encryption, observer, settings.config.dryrun)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn reports_copies_then_skips() {
+        let dir = TempDir::new("report");
+        dir.write("src/a", "a");
+        dir.write("src/sub/b", "b");
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        );
+
+        let report = testing::backup(settings.clone());
+        assert_eq!((report.successes, report.errors), (2, 0));
+        assert_eq!((report.copies, report.skips), (2, 0));
+        assert_eq!(dir.read("out/copy/a").as_deref(), Some("a"));
+        assert_eq!(dir.read("out/copy/sub/b").as_deref(), Some("b"));
+
+        let report = testing::backup(settings);
+        assert_eq!((report.copies, report.skips), (0, 2));
+    }
+
+    #[test]
+    fn dry_runs_copy_nothing() {
+        let dir = TempDir::new("report-dryrun");
+        dir.write("src/a", "a");
+        let mut settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        );
+        settings.config.dryrun = true;
+
+        let report = testing::backup(settings);
+        assert_eq!((report.successes, report.copies), (1, 1));
+        assert_eq!(report.files[0].outcome, Outcome::WouldCopy);
+        assert!(!dir.join("out").exists());
+    }
+
+    fn matches(pattern: &str, name: &str) -> Option<Vec<String>> {
+        let re = component_regex(pattern, true).unwrap();
+        re.captures(name).map(|cap| {
+            cap.iter()
+                .skip(1)
+                .map(|x| x.map(|x| x.as_str().to_owned()).unwrap_or_default())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn stars_capture() {
+        assert_eq!(matches("*", "a.txt"), Some(vec!["a.txt".to_owned()]));
+        assert_eq!(matches("*", ""), Some(vec!["".to_owned()]));
+        assert_eq!(
+            matches("*.*", "a.tar.gz"),
+            Some(vec!["a.tar".to_owned(), "gz".to_owned()])
+        );
+        assert_eq!(matches("*.txt", "a.txt.bak"), None);
+    }
+
+    #[test]
+    fn question_marks_match_one_character() {
+        assert_eq!(matches("?.txt", "a.txt"), Some(vec![]));
+        assert_eq!(matches("?.txt", "é.txt"), Some(vec![]));
+        assert_eq!(matches("?.txt", ".txt"), None);
+        assert_eq!(matches("?.txt", "ab.txt"), None);
+    }
+
+    #[test]
+    fn character_classes() {
+        assert_eq!(matches("[ab].txt", "a.txt"), Some(vec![]));
+        assert_eq!(matches("[ab].txt", "c.txt"), None);
+        assert_eq!(matches("[a-c]*", "cat"), Some(vec!["at".to_owned()]));
+        assert_eq!(matches("[!ab].txt", "c.txt"), Some(vec![]));
+        assert_eq!(matches("[!ab].txt", "a.txt"), None);
+    }
+
+    #[test]
+    fn regex_characters_are_literal() {
+        assert!(matches("a.b", "a.b").is_some());
+        assert!(matches("a.b", "axb").is_none());
+        assert!(matches("a+(1)*", "a+(1)x").is_some());
+        assert!(matches("a+(1)*", "aa(1)x").is_none());
+    }
+
+    #[test]
+    fn regex_components() {
+        assert_eq!(
+            matches(r"re:(\d+)-.*", "2024-report"),
+            Some(vec!["2024".to_owned()])
+        );
+        assert!(matches(r"re:\d+", "2024-report").is_none());
+        assert!(component_regex("re:(", true).is_err());
+    }
+
+    #[test]
+    fn case_sensitivity() {
+        assert!(!component_regex("*.TXT", true).unwrap().is_match("a.txt"));
+        assert!(component_regex("*.TXT", false).unwrap().is_match("a.txt"));
+        assert!(component_regex("re:A", false).unwrap().is_match("a"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(is_wildcard("*"));
+        assert!(is_wildcard("a?"));
+        assert!(is_wildcard("[ab]"));
+        assert!(is_wildcard("re:a"));
+        assert!(!is_wildcard("Documents"));
+        assert!(!is_wildcard("{a,b}"));
+    }
+
+    #[test]
+    fn captures_and_bases() {
+        assert_eq!(
+            capture_names("/home/{user:*}/*/{a,b}/re:x/{dirs:**}/file"),
+            vec![
+                Some("user".to_owned()),
+                None,
+                None,
+                None,
+                Some("dirs".to_owned())
+            ]
+        );
+        assert_eq!(named_capture("{user:*}"), Some(("user", "*")));
+        assert_eq!(named_capture("{a,b}"), None);
+        assert_eq!(named_capture("{:*}"), None);
+
+        assert_eq!(glob_base("/home/*/docs"), PathBuf::from("/home"));
+        assert_eq!(
+            glob_base("/home/user/docs"),
+            PathBuf::from("/home/user/docs")
+        );
+        assert_eq!(glob_base("/home/{user:*}"), PathBuf::from("/home"));
+    }
+
+    #[test]
+    fn brace_filters() {
+        let filter = brace_filter("{Music, -Videos,docs}").unwrap();
+        assert_eq!(filter.only, vec!["music", "docs"]);
+        assert_eq!(filter.exclude, vec!["videos"]);
+        assert!(brace_filter("Music").is_none());
+
+        assert!(passes_filter("MUSIC", &filter));
+        assert!(!passes_filter("Videos", &filter));
+        assert!(!passes_filter("Pictures", &filter));
+        assert!(brace_filter("{user:*}").is_none());
+    }
+
+    #[test]
+    fn filters_per_group() {
+        let entry = SrcFile {
+            from: "/home/{user:*}/*.*".to_owned(),
+            filters: Filters::Named(
+                vec![(
+                    "user".to_owned(),
+                    Match {
+                        exclude: vec!["guest".to_owned()],
+                        only: vec![],
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            ..SrcFile::default()
+        };
+
+        // One filter for `{user:*}`, two empty ones for the groups of `*.*`.
+        let queue = filter_queue(&entry).unwrap();
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue[0].exclude, vec!["guest"]);
+        assert!(queue[1].exclude.is_empty() && queue[2].exclude.is_empty());
+    }
+
+    #[test]
+    fn entries_for_other_platforms_are_validated() {
+        for entry in Settings::default().files {
+            entry.validate().unwrap();
+        }
+
+        for (from, to) in [
+            ("C:\\Users\\{user:*}\\Documents", "${nobody}"),
+            ("C:\\Users\\*", "$2"),
+            ("docs/*", "$DATE{%Q}"),
+        ] {
+            let entry = SrcFile {
+                from: from.to_owned(),
+                to: to.to_owned(),
+                ..SrcFile::default()
+            };
+            assert!(entry.validate().is_err(), "{} {}", from, to);
+        }
+
+        let entry = SrcFile {
+            from: "C:\\Users\\{user:*}".to_owned(),
+            filters: Filters::Named(
+                vec![("nobody".to_owned(), Match::default())]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..SrcFile::default()
+        };
+        assert!(entry.validate().is_err());
+    }
+
+    /// The paths `from` matches under `root`, relative to it and joined with
+    /// `/`, with the name each wildcard component matched.
+    fn globbed(root: &Path, entry: &str) -> Vec<(String, Vec<String>)> {
+        let entry: SrcFile =
+            serde_yaml::from_str(&format!("from: {}/{}\nto: x", root.display(), entry)).unwrap();
+
+        let mut ret: Vec<_> = glob_entry(&entry)
+            .unwrap()
+            .into_iter()
+            .map(|x| {
+                let x = x.unwrap();
+                let path: Vec<_> = x
+                    .path
+                    .strip_prefix(root)
+                    .unwrap()
+                    .iter()
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .collect();
+                (path.join("/"), x.matches)
+            })
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    fn owned(items: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+        items
+            .iter()
+            .map(|(path, matches)| {
+                (
+                    path.to_string(),
+                    matches.iter().map(|x| x.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recursive_wildcards() {
+        let dir = TempDir::new("recursive-wildcards");
+        for file in ["a.txt", "x/b.txt", "x/y/c.txt", "x/y/d.md", "z/e.md"] {
+            dir.write(file, file);
+        }
+
+        assert_eq!(
+            globbed(&dir.path, "**/*.txt"),
+            owned(&[
+                ("a.txt", &["", "a.txt"]),
+                ("x/b.txt", &["x", "b.txt"]),
+                ("x/y/c.txt", &["x/y", "c.txt"]),
+            ])
+        );
+        // A trailing `**` only matches the directory itself.
+        assert_eq!(globbed(&dir.path, "x/**"), owned(&[("x", &[""])]));
+    }
+
+    #[test]
+    fn recursive_wildcard_filters() {
+        let dir = TempDir::new("recursive-wildcard-filters");
+        for file in ["a/1.txt", "a/cache/2.txt", "b/cache/3.txt", "b/4.txt"] {
+            dir.write(file, file);
+        }
+
+        // Excluding a directory skips everything below it.
+        assert_eq!(
+            globbed(&dir.path, "**/*.txt\nfilters:\n  - exclude: [cache]"),
+            owned(&[("a/1.txt", &["a", "1.txt"]), ("b/4.txt", &["b", "4.txt"])])
+        );
+    }
+
+    #[test]
+    fn mirrors_and_the_first_available_fallback() {
+        let dir = TempDir::new("destinations");
+        dir.write("src/a", "a");
+        let dest = |name: &str, label: &str, role: &str| {
+            format!(
+                "  - label: {}\n    format: {}\n    role: {}\n",
+                label,
+                testing::format(&dir.join(name)),
+                role
+            )
+        };
+        let yaml = format!(
+            "config:\n  dryrun: false\ndest:\n{}{}{}{}{}files:\n  - from: {}\n    to: copy\n",
+            dest("mirror", "$CURRENTDRIVE", "mirror"),
+            dest("missing", "mountpoint:/nonexistent/ubackup", "fallback"),
+            dest("fallback", "$CURRENTDRIVE", "fallback"),
+            dest("unused", "$CURRENTDRIVE", "fallback"),
+            dest("unmounted", "mountpoint:/nonexistent/ubackup", "mirror"),
+            dir.join("src").display()
+        );
+
+        let report = testing::backup(serde_yaml::from_str(&yaml).unwrap());
+        assert_eq!(report.copies, 2);
+        assert_eq!(report.destinations.len(), 3);
+        // The unmounted mirror is reported, the missing fallback isn't.
+        assert_eq!(
+            report
+                .destinations
+                .iter()
+                .filter(|x| x.error.is_some())
+                .count(),
+            1
+        );
+        assert_eq!(dir.read("mirror/copy/a").as_deref(), Some("a"));
+        assert_eq!(dir.read("fallback/copy/a").as_deref(), Some("a"));
+        assert!(!dir.join("unused").exists());
+    }
+
+    #[test]
+    fn destinations_without_room_are_skipped() {
+        let dir = TempDir::new("space-check");
+        // Sparse, so it takes no room itself.
+        let big = fs::File::create(dir.join("big")).unwrap();
+        if big.set_len(1 << 42).is_err() {
+            return;
+        }
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: big\n",
+                dir.join("big").display()
+            ),
+        );
+
+        assert!(crate::backup(settings, &mut ()).is_err());
+        assert!(!dir.join("out/big").exists());
+    }
 }
@@ -12,41 +12,353 @@ extern crate serde;
 #[allow(unused_imports)]
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate serde_yaml;
 
 extern crate ubackup;
-use ubackup::Settings;
+use ubackup::{
+    ArchiveEntry, BackupObserver, BackupPlan, BackupReport, Damage, FileReport, Mismatch, Outcome,
+    RestoreOptions, ScrubReport, Settings, VerifyOptions, VerifyReport,
+};
 
-use std::fs::File;
-use std::path::Path;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn main() -> Result<(), Error> {
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_ERRORS: i32 = 1;
+const EXIT_FATAL: i32 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Serialize)]
+struct Event<'a, T: Serialize> {
+    event: &'a str,
+    #[serde(flatten)]
+    data: T,
+}
+
+fn emit<T: Serialize>(event: &str, data: T) {
+    match serde_json::to_string(&Event { event, data }) {
+        Ok(line) => println!("{}", line),
+        Err(e) => eprintln!("{}: {}", event, e),
+    }
+}
+
+#[derive(Serialize)]
+struct Summary {
+    successes: u32,
+    errors: u32,
+    copies: u32,
+    skips: u32,
+    deletes: u32,
+}
+
+impl<'a> From<&'a BackupReport> for Summary {
+    fn from(report: &'a BackupReport) -> Self {
+        Summary {
+            successes: report.successes,
+            errors: report.errors,
+            copies: report.copies,
+            skips: report.skips,
+            deletes: report.deletes,
+        }
+    }
+}
+
+struct ConsoleObserver {
+    quiet: bool,
+    dryrun: bool,
+}
+
+impl BackupObserver for ConsoleObserver {
+    fn file_done(&mut self, file: &FileReport) {
+        if !self.quiet {
+            match file.outcome {
+                Outcome::WouldCopy => println!("{}: Would be copied.", file.src.to_string_lossy()),
+                _ => println!("{}: Copied.", file.src.to_string_lossy()),
+            }
+        }
+    }
+
+    fn file_skipped(&mut self, file: &FileReport) {
+        if !self.quiet {
+            if file.outcome == Outcome::Linked {
+                println!("{}: Linked.", file.src.to_string_lossy());
+            } else if !self.dryrun {
+                println!("{}: Skipped.", file.src.to_string_lossy());
+            } else {
+                println!("{}: Would be skipped.", file.src.to_string_lossy());
+            }
+        }
+    }
+
+    fn file_deleted(&mut self, file: &FileReport) {
+        if !self.quiet {
+            let dest = file.dest.as_ref().unwrap_or(&file.src).to_string_lossy();
+            match file.outcome {
+                Outcome::Trashed => println!("{}: Moved to trash.", dest),
+                Outcome::WouldDelete => println!("{}: Would be deleted.", dest),
+                _ => println!("{}: Deleted.", dest),
+            }
+        }
+    }
+
+    fn error(&mut self, file: &FileReport) {
+        if let Outcome::Failed { ref error } = file.outcome {
+            eprintln!("{}: {}", file.src.to_string_lossy(), error);
+        }
+    }
+
+    fn warning(&mut self, message: &str) {
+        eprintln!("Warning: {}", message);
+    }
+}
+
+struct JsonObserver;
+
+impl BackupObserver for JsonObserver {
+    fn file_done(&mut self, file: &FileReport) {
+        emit("file", file);
+    }
+
+    fn file_skipped(&mut self, file: &FileReport) {
+        emit("file", file);
+    }
+
+    fn file_deleted(&mut self, file: &FileReport) {
+        emit("file", file);
+    }
+
+    fn error(&mut self, file: &FileReport) {
+        emit("file", file);
+    }
+
+    fn warning(&mut self, message: &str) {
+        emit("warning", serde_json::json!({ "message": message }));
+    }
+}
+
+fn main() {
     let cli = clap_app!(uBackup =>
         (version: crate_version!())
-        (author: crate_authors!("\n"))
+        (author: env!("CARGO_PKG_AUTHORS"))
         (about: crate_description!())
         (@arg config: -c --config +takes_value "Config file (yml)")
+        (@arg format: -f --format +takes_value possible_value[text json] "Output format")
         (@group q =>
-            (@arg quiet: -q --quiet "Quiet")
-            (@arg verbose: -v --verbose "Verbose")
+            (@arg quiet: -q --quiet +global "Quiet")
+            (@arg verbose: -v --verbose +global "Verbose")
         )
         (@group r =>
-            (@arg dryrun: -d --dryrun "Dry (don't run)")
-            (@arg run: -r --run "Run")
+            (@arg dryrun: -d --dryrun +global "Dry (don't run)")
+            (@arg run: -r --run +global "Run")
+        )
+        (@subcommand restore =>
+            (about: "Copy backed up files back to their source paths")
+            (@arg from: --from +takes_value "Backup tree to restore from")
+            (@arg root: --root +takes_value "Restore under this directory instead")
+            (@arg keep_newer: -k --("keep-newer") "Don't overwrite files newer than the backup")
+        )
+        (@subcommand plan =>
+            (about: "Write what a backup would do as YAML, or JSON with --format json")
+            (@arg output: -o --output +takes_value "Write the plan to this file instead of stdout")
+        )
+        (@subcommand apply =>
+            (about: "Carry out a plan written by the plan subcommand")
+            (@arg plan: +required "Plan file")
+        )
+        (@subcommand verify =>
+            (about: "Compare the size and contents of every source with its backed up copy")
+            (@arg from: --from +takes_value "Backup tree, archive or snapshot index to verify")
+        )
+        (@subcommand scrub =>
+            (about: "Re-hash backed up files against the manifests written with them")
+            (@arg path: +required "Destination directory to scrub")
+            (@arg jobs: -j --jobs +takes_value "Files hashed at once (default: one per CPU)")
+        )
+        (@subcommand list =>
+            (about: "List the files in a .tar.zst or .zip backup archive or repository snapshot")
+            (@arg archive: +required "Archive or snapshot index file")
+        )
+        (@subcommand extract =>
+            (about: "Extract a .tar.zst or .zip backup archive or repository snapshot")
+            (@arg archive: +required "Archive or snapshot index file")
+            (@arg to: +required "Directory to extract to")
         )
     );
-    let cli: clap::ArgMatches = cli.get_matches();
+    let cli: clap::ArgMatches = match cli.get_matches_safe() {
+        Ok(cli) => cli,
+        // Clap reports `--help` and `--version` as errors too.
+        Err(e) => {
+            if e.use_stderr() {
+                eprintln!("{}", e.message);
+                process::exit(EXIT_FATAL);
+            }
+            println!("{}", e.message);
+            process::exit(EXIT_SUCCESS);
+        }
+    };
+
+    let format = match cli.value_of("format") {
+        Some("json") => Format::Json,
+        _ => Format::Text,
+    };
+
+    process::exit(match run(&cli, format) {
+        Ok(code) => code,
+        Err(e) => {
+            match format {
+                Format::Text => eprintln!("{}", e),
+                Format::Json => emit("fatal", serde_json::json!({ "error": e.to_string() })),
+            }
+            EXIT_FATAL
+        }
+    });
+}
+
+fn print_verify(report: &VerifyReport, format: Format) {
+    match format {
+        Format::Text => {
+            for file in &report.files {
+                let src = file.src.as_deref().unwrap_or(&file.dest).to_string_lossy();
+                let dest = file.dest.to_string_lossy();
+                match file.mismatch {
+                    Mismatch::Missing => println!("{}: Missing from backup at {}.", src, dest),
+                    Mismatch::Differs { ref reason } => {
+                        println!("{}: Differs from {} ({}).", src, dest, reason)
+                    }
+                    Mismatch::Extra => println!("{}: Not backed up from any source.", dest),
+                    Mismatch::Failed { ref error } => eprintln!("{}: {}", src, error),
+                }
+            }
+
+            println!(
+                "{} matches, {} missing, {} differing, {} extra, {} errors",
+                report.matches, report.missing, report.differing, report.extra, report.errors
+            )
+        }
+        Format::Json => {
+            for file in &report.files {
+                emit("mismatch", file);
+            }
+            emit(
+                "verify_summary",
+                serde_json::json!({
+                    "from": report.from,
+                    "matches": report.matches,
+                    "missing": report.missing,
+                    "differing": report.differing,
+                    "extra": report.extra,
+                    "errors": report.errors,
+                }),
+            )
+        }
+    }
+}
+
+fn print_scrub(report: &ScrubReport, format: Format) {
+    match format {
+        Format::Text => {
+            for file in &report.files {
+                let path = file.path.to_string_lossy();
+                match file.damage {
+                    Damage::Missing => println!("{}: Missing.", path),
+                    Damage::Corrupt { ref reason } => println!("{}: Corrupt ({}).", path, reason),
+                    Damage::Failed { ref error } => eprintln!("{}: {}", path, error),
+                }
+            }
+
+            println!(
+                "{} manifests, {} intact, {} missing, {} corrupt, {} errors",
+                report.manifests, report.intact, report.missing, report.corrupt, report.errors
+            )
+        }
+        Format::Json => {
+            for file in &report.files {
+                emit("damage", file);
+            }
+            emit(
+                "scrub_summary",
+                serde_json::json!({
+                    "manifests": report.manifests,
+                    "intact": report.intact,
+                    "missing": report.missing,
+                    "corrupt": report.corrupt,
+                    "errors": report.errors,
+                }),
+            )
+        }
+    }
+}
+
+/// Prints the files of an archive, listed or extracted.
+fn print_entries(entries: &[ArchiveEntry], format: Format) {
+    for entry in entries {
+        match format {
+            Format::Text => match entry.error {
+                Some(ref error) => eprintln!("{}: {}", entry.path.to_string_lossy(), error),
+                None => println!("{:>12}  {}", entry.bytes, entry.path.to_string_lossy()),
+            },
+            Format::Json => emit("entry", entry),
+        }
+    }
+}
+
+fn run(cli: &clap::ArgMatches, format: Format) -> Result<i32, Error> {
+    // Archives and manifests are read without a config.
+    match cli.subcommand() {
+        ("list", Some(sub)) => {
+            let entries = ubackup::list(Path::new(sub.value_of("archive").unwrap()))?;
+            print_entries(&entries, format);
+            return Ok(EXIT_SUCCESS);
+        }
+        ("scrub", Some(sub)) => {
+            let jobs = match sub.value_of("jobs") {
+                Some(jobs) => jobs
+                    .parse()
+                    .map_err(|_| format_err!("invalid number of jobs: {}", jobs))?,
+                None => 0,
+            };
+            let report = ubackup::scrub(Path::new(sub.value_of("path").unwrap()), jobs)?;
+            print_scrub(&report, format);
+            return Ok(if report.is_ok() {
+                EXIT_SUCCESS
+            } else {
+                EXIT_ERRORS
+            });
+        }
+        ("extract", Some(sub)) => {
+            let entries = ubackup::extract(
+                Path::new(sub.value_of("archive").unwrap()),
+                Path::new(sub.value_of("to").unwrap()),
+            )?;
+            print_entries(&entries, format);
+            return Ok(if entries.iter().all(|x| x.error.is_none()) {
+                EXIT_SUCCESS
+            } else {
+                EXIT_ERRORS
+            });
+        }
+        _ => {}
+    }
 
     let config_file = cli.value_of("config").unwrap_or("config.yaml");
     let config_path = Path::new(config_file);
 
     if !config_path.exists() {
-        println!("Creating default config at {}.", config_file);
+        match format {
+            Format::Text => println!("Creating default config at {}.", config_file),
+            Format::Json => emit("config_created", serde_json::json!({ "path": config_file })),
+        }
 
-        return Ok(serde_yaml::to_writer(
-            File::create(config_path)?,
-            &Settings::new(None)?,
-        )?);
+        serde_yaml::to_writer(File::create(config_path)?, &Settings::new(None)?)?;
+        return Ok(EXIT_SUCCESS);
     }
 
     let mut settings = Settings::new(Some(config_file))?;
@@ -65,11 +377,105 @@ fn main() -> Result<(), Error> {
         settings.config.dryrun = false;
     }
 
-    let [successes, errors, copied, skiped]: [u32; 4] = ubackup::backup(settings.clone())?;
+    let mut observer: Box<dyn BackupObserver> = match format {
+        Format::Text => Box::new(ConsoleObserver {
+            quiet: settings.config.quiet,
+            dryrun: settings.config.dryrun,
+        }),
+        Format::Json => Box::new(JsonObserver),
+    };
+    if let ("plan", Some(sub)) = cli.subcommand() {
+        let plan = ubackup::plan(&settings)?;
+        let text = match format {
+            Format::Text => serde_yaml::to_string(&plan)?,
+            Format::Json => serde_json::to_string_pretty(&plan)?,
+        };
 
-    println!(
-        "{} successes, {} errors, {} copies, {} skips",
-        successes, errors, copied, skiped
-    );
-    Ok(())
+        match sub.value_of("output") {
+            Some(output) => fs::write(output, text)?,
+            None => println!("{}", text),
+        }
+
+        return Ok(EXIT_SUCCESS);
+    }
+
+    if let ("verify", Some(sub)) = cli.subcommand() {
+        let report = ubackup::verify(
+            &settings,
+            VerifyOptions {
+                from: sub.value_of("from").map(PathBuf::from),
+            },
+            observer.as_mut(),
+        )?;
+        print_verify(&report, format);
+
+        return Ok(if report.is_ok() {
+            EXIT_SUCCESS
+        } else {
+            EXIT_ERRORS
+        });
+    }
+
+    let report = match cli.subcommand() {
+        ("restore", Some(sub)) => ubackup::restore(
+            settings.clone(),
+            RestoreOptions {
+                from: sub.value_of("from").map(PathBuf::from),
+                root: sub.value_of("root").map(PathBuf::from),
+                keep_newer: sub.is_present("keep_newer"),
+            },
+            observer.as_mut(),
+        )?,
+        ("apply", Some(sub)) => {
+            let plan: BackupPlan =
+                serde_yaml::from_reader(File::open(sub.value_of("plan").unwrap())?)?;
+            ubackup::apply(plan, &settings, observer.as_mut())?
+        }
+        _ => ubackup::backup(settings.clone(), observer.as_mut())?,
+    };
+
+    match format {
+        Format::Text => {
+            if !settings.config.quiet {
+                for path in &report.pruned {
+                    println!("{}: Pruned.", path.to_string_lossy());
+                }
+            }
+
+            if report.destinations.len() > 1 {
+                for dest in &report.destinations {
+                    match (&dest.root, &dest.error) {
+                        (_, Some(error)) => eprintln!("{}: {}", dest.label, error),
+                        (Some(root), None) => println!(
+                            "{}: {} successes, {} errors, {} copies, {} skips, {} deletes",
+                            root.to_string_lossy(),
+                            dest.successes,
+                            dest.errors,
+                            dest.copies,
+                            dest.skips,
+                            dest.deletes
+                        ),
+                        (None, None) => {}
+                    }
+                }
+            }
+
+            println!(
+                "{} successes, {} errors, {} copies, {} skips, {} deletes",
+                report.successes, report.errors, report.copies, report.skips, report.deletes
+            )
+        }
+        Format::Json => {
+            for dest in &report.destinations {
+                emit("destination", dest);
+            }
+            emit("summary", Summary::from(&report))
+        }
+    }
+
+    if report.errors > 0 {
+        Ok(EXIT_ERRORS)
+    } else {
+        Ok(EXIT_SUCCESS)
+    }
 }
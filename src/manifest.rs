@@ -0,0 +1,349 @@
+use crate::archive;
+use crate::atomic;
+use crate::compare;
+use crate::pool;
+use crate::repository;
+use crate::settings::Compare;
+use failure::Error;
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+pub(crate) const MANIFEST_FILE: &str = ".ubackup-manifest.json";
+
+/// What a backed up file contained when it was written.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Stored {
+    bytes: u64,
+    mtime: (u64, u32),
+    /// BLAKE3 hash of the stored bytes, encrypted or not.
+    hash: String,
+}
+
+fn mtime(md: &Metadata) -> (u64, u32) {
+    md.modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| (x.as_secs(), x.subsec_nanos()))
+        .unwrap_or_default()
+}
+
+fn load(path: &Path) -> BTreeMap<String, Stored> {
+    File::open(path)
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default()
+}
+
+/// The size, mtime and hash of every file backed up under a directory: a
+/// destination root, or a snapshot.
+///
+/// The manifest is kept in the directory itself, so the files can be checked
+/// for corruption with `scrub` without the sources or the key they were
+/// encrypted with.
+pub(crate) struct Manifest {
+    dir: PathBuf,
+    files: Mutex<BTreeMap<String, Stored>>,
+    /// The manifest of the previous snapshot, for the files linked from it.
+    previous: BTreeMap<String, Stored>,
+}
+
+impl Manifest {
+    /// Reads the manifest under `dir`, if any, and the one under the
+    /// `previous` snapshot.
+    pub fn load(dir: &Path, previous: Option<&Path>) -> Manifest {
+        Manifest {
+            dir: dir.to_owned(),
+            files: Mutex::new(load(&dir.join(MANIFEST_FILE))),
+            previous: previous
+                .map(|x| load(&x.join(MANIFEST_FILE)))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The key of `path`, if it is under the directory of the manifest.
+    /// Other paths are never recorded.
+    fn key(&self, path: &Path) -> Option<String> {
+        path.strip_prefix(&self.dir).ok().map(archive::entry_name)
+    }
+
+    /// Records the file written at `path`. Hard links to the previous
+    /// snapshot keep its hash instead of being read again.
+    pub fn record(&self, path: &Path) -> Result<(), Error> {
+        let key = match self.key(path) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let md = path.metadata()?;
+
+        let stored = match self.previous.get(&key) {
+            Some(x) if x.bytes == md.len() && x.mtime == mtime(&md) => x.clone(),
+            _ => Stored {
+                bytes: md.len(),
+                mtime: mtime(&md),
+                hash: compare::hash_file(path, Compare::Blake3)?,
+            },
+        };
+
+        self.files.lock().unwrap().insert(key, stored);
+        Ok(())
+    }
+
+    /// Records the file at `path` if the manifest doesn't list it yet, as
+    /// for files backed up before manifests were kept.
+    pub fn skipped(&self, path: &Path) -> Result<(), Error> {
+        match self.key(path) {
+            Some(key) if path.is_file() && !self.files.lock().unwrap().contains_key(&key) => {
+                self.record(path)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets the file or directory removed from `path`.
+    pub fn remove(&self, path: &Path) {
+        let key = match self.key(path) {
+            Some(key) => key,
+            None => return,
+        };
+        let dir = format!("{}/", key);
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|x, _| *x != key && !x.starts_with(&dir));
+    }
+
+    pub fn save(self) -> Result<(), Error> {
+        let files = self.files.into_inner().unwrap();
+        atomic::write_file(&self.dir.join(MANIFEST_FILE), &serde_json::to_vec(&files)?)
+    }
+}
+
+/// How a stored file no longer matches its manifest.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum Damage {
+    /// A file the manifest lists is gone.
+    Missing,
+    Corrupt {
+        reason: String,
+    },
+    /// The file could not be read.
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ScrubbedFile {
+    #[serde(flatten)]
+    pub damage: Damage,
+    pub path: PathBuf,
+}
+
+/// What `scrub` found. Intact files are only counted.
+#[derive(Debug, Default, Serialize, Clone)]
+pub struct ScrubReport {
+    /// Manifests and repositories checked.
+    pub manifests: u32,
+    pub intact: u32,
+    pub missing: u32,
+    pub corrupt: u32,
+    pub errors: u32,
+    pub files: Vec<ScrubbedFile>,
+}
+
+impl ScrubReport {
+    fn push(&mut self, path: PathBuf, damage: Option<Damage>) {
+        match damage {
+            None => {
+                self.intact += 1;
+                return;
+            }
+            Some(Damage::Missing) => self.missing += 1,
+            Some(Damage::Corrupt { .. }) => self.corrupt += 1,
+            Some(Damage::Failed { .. }) => self.errors += 1,
+        }
+
+        self.files.push(ScrubbedFile {
+            damage: damage.unwrap(),
+            path,
+        });
+    }
+
+    /// Whether every file matches its manifest.
+    pub fn is_ok(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// Compares the file at `path` with the size and hash it was stored with.
+/// Chunks of a repository are only checked against their hash.
+fn check(path: &Path, bytes: Option<u64>, hash: &str) -> Option<Damage> {
+    let md = match path.metadata() {
+        Ok(md) if md.is_file() => md,
+        _ => return Some(Damage::Missing),
+    };
+
+    let reason = if bytes.is_some_and(|bytes| bytes != md.len()) {
+        "size"
+    } else {
+        match compare::hash_file(path, Compare::Blake3) {
+            Ok(found) if found == hash => return None,
+            Ok(_) => "contents",
+            Err(e) => {
+                return Some(Damage::Failed {
+                    error: e.to_string(),
+                })
+            }
+        }
+    };
+
+    Some(Damage::Corrupt {
+        reason: reason.to_owned(),
+    })
+}
+
+/// Lists the directories under `dir` with a manifest, and the repositories.
+/// The chunks and indexes of a repository are checked against their hashes
+/// instead, but anything else under its root is looked through.
+fn find_manifests(dir: &Path, found: &mut Vec<PathBuf>, repositories: &mut Vec<PathBuf>) {
+    if repository::is_internal(dir) {
+        return;
+    }
+
+    if repository::is_repository(dir) {
+        repositories.push(dir.to_owned());
+    }
+
+    if dir.join(MANIFEST_FILE).is_file() {
+        found.push(dir.to_owned());
+    }
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        let mut dirs: Vec<PathBuf> = entries
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_ok_and(|x| x.is_dir()))
+            .map(|x| x.path())
+            .collect();
+        dirs.sort();
+
+        for dir in dirs {
+            find_manifests(&dir, found, repositories);
+        }
+    }
+}
+
+/// Hashes every file the manifests under `path` list, and every chunk of the
+/// repositories there, on up to `jobs` threads. Reports the ones that are
+/// missing or no longer match.
+pub fn scrub(path: &Path, jobs: usize) -> Result<ScrubReport, Error> {
+    if !path.is_dir() {
+        return Err(format_err!("backup not found: {}", path.to_string_lossy()));
+    }
+
+    let mut dirs = vec![];
+    let mut repositories = vec![];
+    find_manifests(path, &mut dirs, &mut repositories);
+
+    let mut report = ScrubReport::default();
+    let mut files: Vec<(PathBuf, Option<u64>, String)> = vec![];
+    for dir in dirs {
+        report.manifests += 1;
+        files.extend(
+            load(&dir.join(MANIFEST_FILE))
+                .into_iter()
+                .map(|(key, stored)| (dir.join(key), Some(stored.bytes), stored.hash)),
+        );
+    }
+    for root in repositories {
+        report.manifests += 1;
+        files.extend(
+            repository::chunks(&root)?
+                .into_iter()
+                .map(|(path, hash)| (path, None, hash.to_hex().to_string())),
+        );
+    }
+
+    pool::for_each_ordered(
+        &files,
+        jobs,
+        |(path, bytes, hash)| check(path, *bytes, hash),
+        |(path, _, _), damage| report.push(path.clone(), damage),
+    );
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn backed_up(test: &str) -> TempDir {
+        let dir = TempDir::new(test);
+        dir.write("src/a", "first");
+        dir.write("src/b", "second");
+        dir.write("src/c", "third");
+        testing::backup(testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        ));
+        assert!(dir.join("out").join(MANIFEST_FILE).is_file());
+        dir
+    }
+
+    #[test]
+    fn backups_are_intact() {
+        let dir = backed_up("scrub-intact");
+        let report = scrub(&dir.join("out"), 2).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.manifests, report.intact), (1, 3));
+    }
+
+    #[test]
+    fn damage_is_reported() {
+        let dir = backed_up("scrub-damage");
+        dir.write("out/copy/a", "fir5t");
+        dir.write("out/copy/b", "second, longer");
+        fs::remove_file(dir.join("out/copy/c")).unwrap();
+
+        let report = scrub(&dir.join("out"), 2).unwrap();
+        assert_eq!((report.intact, report.corrupt, report.missing), (0, 2, 1));
+        let mut reasons = report
+            .files
+            .iter()
+            .filter_map(|x| match x.damage {
+                Damage::Corrupt { ref reason } => Some(reason.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        reasons.sort();
+        assert_eq!(reasons, vec!["contents", "size"]);
+    }
+
+    #[test]
+    fn repository_roots_are_looked_through() {
+        let dir = backed_up("scrub-repository");
+        fs::create_dir_all(dir.join("out/chunks")).unwrap();
+        fs::create_dir_all(dir.join("out/snapshots")).unwrap();
+        // A manifest among the chunks is not the repository's.
+        dir.write(&format!("out/chunks/{}", MANIFEST_FILE), "{}");
+
+        let report = scrub(&dir.join("out"), 2).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.manifests, report.intact), (2, 3));
+    }
+
+    #[test]
+    fn missing_backups_are_errors() {
+        let dir = TempDir::new("scrub-missing");
+        assert!(scrub(&dir.join("out"), 1).is_err());
+    }
+}
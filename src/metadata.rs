@@ -0,0 +1,184 @@
+use crate::settings::Preserve;
+use failure::Error;
+use std::fs::{File, FileTimes, Metadata};
+use std::io;
+use std::path::Path;
+
+/// Whether an error only means the destination or the user running the
+/// backup can't hold some metadata, which is not worth failing a copy for.
+#[cfg(unix)]
+fn is_unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::PermissionDenied
+        || e.kind() == io::ErrorKind::Unsupported
+        || e.raw_os_error() == Some(95) // EOPNOTSUPP
+}
+
+#[cfg(unix)]
+fn ignore_unsupported(ret: io::Result<()>) -> Result<(), Error> {
+    match ret {
+        Err(ref e) if is_unsupported(e) => Ok(()),
+        ret => Ok(ret?),
+    }
+}
+
+#[cfg(unix)]
+fn copy_ownership(md: &Metadata, file: &File) -> Result<(), Error> {
+    use std::os::unix::fs::{fchown, MetadataExt};
+
+    ignore_unsupported(fchown(file, Some(md.uid()), Some(md.gid())))
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_md: &Metadata, _file: &File) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_permissions(md: &Metadata, file: &File) -> Result<(), Error> {
+    Ok(file.set_permissions(md.permissions())?)
+}
+
+/// The read-only flag is left off, since Windows refuses to replace or
+/// remove a read-only file, as the next run or pruning a snapshot would.
+#[cfg(not(unix))]
+fn copy_permissions(_md: &Metadata, _file: &File) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_xattrs(src: &Path, file: &File) -> Result<(), Error> {
+    use xattr::FileExt;
+
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        Err(ref e) if is_unsupported(e) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for name in names {
+        if let Some(value) = xattr::get(src, &name)? {
+            ignore_unsupported(file.set_xattr(&name, &value))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_xattrs(_src: &Path, _file: &File) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Copies the metadata `preserve` selects from `src` to `file`, a copy of it.
+///
+/// Ownership comes before permissions, since changing the owner can clear the
+/// setuid and setgid bits, and times come last, since the others can change
+/// them.
+pub(crate) fn copy(src: &Path, file: &File, preserve: &Preserve) -> Result<(), Error> {
+    let md = src.metadata()?;
+
+    if preserve.xattrs {
+        copy_xattrs(src, file)?;
+    }
+
+    if preserve.ownership {
+        copy_ownership(&md, file)?;
+    }
+
+    if preserve.permissions {
+        copy_permissions(&md, file)?;
+    }
+
+    if preserve.times {
+        file.set_times(
+            FileTimes::new()
+                .set_accessed(md.accessed()?)
+                .set_modified(md.modified()?),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+    use std::time::{Duration, SystemTime};
+
+    fn copied(test: &str, preserve: &Preserve) -> (Metadata, Metadata) {
+        let dir = TempDir::new(test);
+        let src = dir.write("src", "contents");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let dest = dir.write("dest", "contents");
+        copy(
+            &src,
+            &File::options().write(true).open(&dest).unwrap(),
+            preserve,
+        )
+        .unwrap();
+        (src.metadata().unwrap(), dest.metadata().unwrap())
+    }
+
+    #[test]
+    fn times_are_copied() {
+        let (src, dest) = copied("metadata-times", &Preserve::default());
+        assert_eq!(dest.modified().unwrap(), src.modified().unwrap());
+    }
+
+    #[test]
+    fn unselected_metadata_is_left_alone() {
+        let preserve = Preserve {
+            times: false,
+            permissions: false,
+            ownership: false,
+            xattrs: false,
+        };
+        let (src, dest) = copied("metadata-none", &preserve);
+        assert_ne!(dest.modified().unwrap(), src.modified().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions_are_copied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("metadata-permissions");
+        let src = dir.write("src", "contents");
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let dest = dir.write("dest", "contents");
+        copy(&src, &File::open(&dest).unwrap(), &Preserve::default()).unwrap();
+        assert_eq!(dest.metadata().unwrap().permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn read_only_copies_are_replaced() {
+        let dir = TempDir::new("metadata-read-only");
+        let src = dir.write("src/a", "old");
+        let writable = src.metadata().unwrap().permissions();
+        let mut read_only = writable.clone();
+        read_only.set_readonly(true);
+        std::fs::set_permissions(&src, read_only).unwrap();
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        );
+        testing::backup(settings.clone());
+
+        std::fs::set_permissions(&src, writable).unwrap();
+        std::fs::write(&src, "new, longer").unwrap();
+        let report = testing::backup(settings);
+        assert_eq!(report.errors, 0);
+        assert_eq!(dir.read("out/copy/a").as_deref(), Some("new, longer"));
+    }
+}
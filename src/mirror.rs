@@ -0,0 +1,316 @@
+use crate::plan::{Action, PlannedFile};
+use crate::restore::{source_from_captures, unglob};
+use crate::settings::{Mirror, SrcFile};
+use crate::{glob_base, Run, RESERVED_PREFIX};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where `Mirror::Trash` moves files, under the destination root.
+pub(crate) const TRASH_DIR: &str = ".ubackup-deleted";
+
+fn files_under(path: &Path, files: &mut Vec<(PathBuf, u64)>) {
+    match fs::read_dir(path) {
+        Ok(entries) => {
+            let before = files.len();
+            for entry in entries.filter_map(|x| x.ok()) {
+                files_under(&entry.path(), files);
+            }
+
+            if files.len() == before {
+                files.push((path.to_owned(), 0));
+            }
+        }
+        Err(_) => {
+            let size = path.metadata().map(|md| md.len()).unwrap_or_default();
+            files.push((path.to_owned(), size));
+        }
+    }
+}
+
+/// Plans deleting `backup`, or moving it under `trash`, file by file.
+fn remove(source: &Path, backup: &Path, entry: &SrcFile, root: &Path, trash: &Path, run: &mut Run) {
+    let mut files = vec![];
+    files_under(backup, &mut files);
+
+    for (file, bytes) in files {
+        let src = match file.strip_prefix(backup) {
+            Ok(rel) if rel != Path::new("") => source.join(run.names.source_path(rel)),
+            _ => source.to_owned(),
+        };
+
+        let action = match entry.mirror {
+            Mirror::Trash => Action::Trash {
+                target: trash.join(file.strip_prefix(root).unwrap_or(&file)),
+            },
+            _ => Action::Delete,
+        };
+
+        run.record(PlannedFile {
+            action,
+            src,
+            dest: Some(file),
+            bytes,
+            entry: entry.clone(),
+        });
+    }
+}
+
+fn mirror_dir(
+    source: &Path,
+    backup: &Path,
+    entry: &SrcFile,
+    root: &Path,
+    trash: &Path,
+    run: &mut Run,
+) {
+    // Only a source that is certainly gone has its backup removed.
+    let exists = match fs::symlink_metadata(source) {
+        Ok(_) => true,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => {
+            run.observer.warning(&format!(
+                "not mirroring {}: {}",
+                source.to_string_lossy(),
+                e
+            ));
+            return;
+        }
+    };
+    let is_dir = exists && source.is_dir();
+
+    if !exists || run.is_ignored(source, is_dir) {
+        remove(source, backup, entry, root, trash, run);
+        return;
+    }
+
+    if !(is_dir && backup.is_dir()) {
+        return;
+    }
+
+    match fs::read_dir(backup) {
+        Ok(entries) => {
+            for file in entries.filter_map(|x| x.ok()) {
+                // Partial files, manifests, journals and the trash are
+                // ubackup's own, and never come from a source.
+                if file
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(RESERVED_PREFIX)
+                {
+                    continue;
+                }
+
+                let name = match run.names.source_name(&file.file_name()) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                mirror_dir(&source.join(&name), &file.path(), entry, root, trash, run);
+            }
+        }
+        Err(e) => run.record(PlannedFile::failed(
+            source.to_owned(),
+            Some(backup.to_owned()),
+            entry,
+            e,
+        )),
+    }
+}
+
+/// Plans removing the files under `root` that `entry` produced from sources that no
+/// longer exist.
+///
+/// Only backed up paths whose captures pass the filters of `entry` are
+/// considered (see `unglob`), so files that other entries wrote are left alone.
+///
+/// Nothing is removed when the directory the sources are found in is missing,
+/// as when a drive isn't mounted, or when `entry` matched nothing (`matched`
+/// is 0). That is reported as an error instead.
+pub(crate) fn mirror(entry: &SrcFile, matched: usize, root: &Path, trash: &Path, run: &mut Run) {
+    let base = glob_base(&entry.from);
+    let refused = if !base.exists() {
+        Some(format!(
+            "not mirroring, source directory is missing: {}",
+            base.to_string_lossy()
+        ))
+    } else if matched == 0 {
+        Some("not mirroring, nothing matched".to_owned())
+    } else {
+        None
+    };
+    if let Some(error) = refused {
+        run.record(PlannedFile::failed(
+            PathBuf::from(&entry.from),
+            None,
+            entry,
+            error,
+        ));
+        return;
+    }
+
+    let found = match unglob(root, entry, run.names.cipher()) {
+        Ok(found) => found,
+        Err(e) => {
+            run.record(PlannedFile::failed(
+                PathBuf::from(&entry.from),
+                None,
+                entry,
+                e,
+            ));
+            return;
+        }
+    };
+
+    for found in found {
+        match source_from_captures(entry, root, &found, run.names.cipher()) {
+            Ok(source) => {
+                run.base = source.clone();
+                mirror_dir(&source, &found.path, entry, root, trash, run);
+            }
+            Err(e) => run.observer.warning(&format!(
+                "not mirroring {}: {}",
+                found.path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::BackupObserver;
+    use crate::testing::{self, TempDir};
+    use crate::{BackupReport, Outcome, Settings};
+
+    fn settings(dir: &TempDir, mirror: &str) -> Settings {
+        testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n    mirror: {}\n",
+                dir.join("src").display(),
+                mirror
+            ),
+        )
+    }
+
+    /// Backs up `a` and `sub/b`, then removes `sub` from the source.
+    fn removed_source(test: &str) -> TempDir {
+        let dir = TempDir::new(test);
+        dir.write("src/a", "a");
+        dir.write("src/sub/b", "b");
+        testing::backup(settings(&dir, "off"));
+
+        fs::remove_dir_all(dir.join("src/sub")).unwrap();
+        dir
+    }
+
+    fn outcomes(report: &BackupReport) -> Vec<Outcome> {
+        report.files.iter().map(|x| x.outcome.clone()).collect()
+    }
+
+    #[test]
+    fn stale_backups_are_deleted() {
+        let dir = removed_source("mirror-delete");
+
+        let report = testing::backup(settings(&dir, "delete"));
+        assert_eq!(outcomes(&report), vec![Outcome::Skipped, Outcome::Deleted]);
+        assert_eq!(report.files[1].src, dir.join("src/sub/b"));
+        assert!(dir.join("out/copy/a").is_file());
+        assert!(!dir.join("out/copy/sub").exists());
+    }
+
+    #[test]
+    fn stale_backups_are_trashed() {
+        let dir = removed_source("mirror-trash");
+
+        let report = testing::backup(settings(&dir, "trash"));
+        assert_eq!(outcomes(&report), vec![Outcome::Skipped, Outcome::Trashed]);
+        assert!(!dir.join("out/copy/sub").exists());
+        // Under a directory named after the run.
+        let run = fs::read_dir(dir.join("out").join(TRASH_DIR))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(run.path().join("copy/sub/b")).unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn dry_runs_and_plain_entries_keep_backups() {
+        let dir = removed_source("mirror-dryrun");
+
+        let mut dryrun = settings(&dir, "delete");
+        dryrun.config.dryrun = true;
+        let report = testing::backup(dryrun);
+        assert_eq!(
+            outcomes(&report),
+            vec![Outcome::Skipped, Outcome::WouldDelete]
+        );
+
+        let report = testing::backup(settings(&dir, "off"));
+        assert_eq!(outcomes(&report), vec![Outcome::Skipped]);
+        assert!(dir.join("out/copy/sub/b").is_file());
+    }
+
+    #[test]
+    fn reserved_names_are_kept() {
+        let dir = removed_source("mirror-reserved");
+        dir.write("out/copy/.ubackup-notes", "kept");
+
+        let report = testing::backup(settings(&dir, "delete"));
+        assert_eq!(outcomes(&report), vec![Outcome::Skipped, Outcome::Deleted]);
+        assert_eq!(report.files[1].dest, Some(dir.join("out/copy/sub/b")));
+        assert_eq!(dir.read("out/copy/.ubackup-notes").as_deref(), Some("kept"));
+    }
+
+    #[derive(Default)]
+    struct Warnings(Vec<String>);
+
+    impl BackupObserver for Warnings {
+        fn warning(&mut self, message: &str) {
+            self.0.push(message.to_owned());
+        }
+    }
+
+    #[test]
+    fn untraceable_backups_are_kept_with_a_warning() {
+        let dir = TempDir::new("mirror-untraceable");
+        dir.write("src/alice/a.txt", "a");
+        dir.write("src/bob/b.txt", "b");
+        // The name of each user is not in `to`, so a backup is traced back to
+        // its source by globbing.
+        let settings = |mirror: &str| {
+            testing::settings(
+                &dir.join("out"),
+                &format!(
+                    "files:\n  - from: {}/*/*\n    to: users/$2\n    mirror: {}\n",
+                    dir.join("src").display(),
+                    mirror
+                ),
+            )
+        };
+        testing::backup(settings("off"));
+        fs::remove_file(dir.join("src/alice/a.txt")).unwrap();
+
+        let mut warnings = Warnings::default();
+        crate::backup(settings("delete"), &mut warnings).unwrap();
+        assert_eq!(warnings.0.len(), 1);
+        assert!(warnings.0[0].contains("users/a.txt"));
+        assert!(dir.join("out/users/a.txt").is_file());
+    }
+
+    #[test]
+    fn missing_sources_delete_nothing() {
+        let dir = removed_source("mirror-missing");
+        fs::remove_dir_all(dir.join("src")).unwrap();
+
+        let report = testing::backup(settings(&dir, "delete"));
+        assert_eq!(report.errors, 1);
+        assert!(dir.join("out/copy/a").is_file());
+        assert!(dir.join("out/copy/sub/b").is_file());
+    }
+}
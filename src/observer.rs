@@ -0,0 +1,108 @@
+use crate::report::{FileReport, Outcome};
+use crate::settings::SrcFile;
+use std::path::Path;
+
+/// Receives progress events while `backup` runs.
+///
+/// Every method has an empty default, so implementors only override the
+/// events they care about. `()` is a no-op observer.
+pub trait BackupObserver {
+    /// A `from` glob matched `path`.
+    fn glob_expanded(&mut self, _entry: &SrcFile, _path: &Path) {}
+
+    /// `src` is about to be copied or linked to `dest`, or skipped. With
+    /// several `jobs` this is reported as a worker picks the file up, so it
+    /// can arrive while the copy is under way, but always before its outcome.
+    fn file_started(&mut self, _src: &Path, _dest: &Path) {}
+
+    /// A file was copied (or would be copied during a dry run).
+    fn file_done(&mut self, _file: &FileReport) {}
+
+    /// A file was skipped because the destination is up to date, or was
+    /// hard-linked from the previous snapshot.
+    fn file_skipped(&mut self, _file: &FileReport) {}
+
+    /// A mirrored file whose source is gone was deleted or moved to the trash
+    /// (or would be during a dry run).
+    fn file_deleted(&mut self, _file: &FileReport) {}
+
+    /// A file, directory or glob failed.
+    fn error(&mut self, _file: &FileReport) {}
+
+    /// Something worth knowing that didn't stop the backup, such as a
+    /// destination that may run out of space.
+    fn warning(&mut self, _message: &str) {}
+}
+
+impl BackupObserver for () {}
+
+/// Passes `file` to the observer method for its outcome.
+pub(crate) fn notify(observer: &mut dyn BackupObserver, file: &FileReport) {
+    match file.outcome {
+        Outcome::Copied | Outcome::WouldCopy => observer.file_done(file),
+        Outcome::Skipped | Outcome::Linked => observer.file_skipped(file),
+        Outcome::Deleted | Outcome::Trashed | Outcome::WouldDelete => observer.file_deleted(file),
+        Outcome::Failed { .. } => observer.error(file),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[derive(Default)]
+    struct Recorder {
+        globs: usize,
+        started: usize,
+        done: usize,
+        skipped: usize,
+    }
+
+    impl BackupObserver for Recorder {
+        fn glob_expanded(&mut self, _entry: &SrcFile, _path: &Path) {
+            self.globs += 1;
+        }
+
+        fn file_started(&mut self, _src: &Path, _dest: &Path) {
+            self.started += 1;
+        }
+
+        fn file_done(&mut self, _file: &FileReport) {
+            self.done += 1;
+        }
+
+        fn file_skipped(&mut self, _file: &FileReport) {
+            self.skipped += 1;
+        }
+    }
+
+    #[test]
+    fn observers_see_every_file() {
+        let dir = TempDir::new("observer");
+        dir.write("src/a", "a");
+        dir.write("src/b", "b");
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        );
+
+        let mut recorder = Recorder::default();
+        crate::backup(settings.clone(), &mut recorder).unwrap();
+        assert_eq!(recorder.globs, 1);
+        assert_eq!(
+            (recorder.started, recorder.done, recorder.skipped),
+            (2, 2, 0)
+        );
+
+        let mut recorder = Recorder::default();
+        crate::backup(settings, &mut recorder).unwrap();
+        assert_eq!(
+            (recorder.started, recorder.done, recorder.skipped),
+            (2, 0, 2)
+        );
+    }
+}
@@ -0,0 +1,604 @@
+use crate::archive;
+use crate::atomic;
+use crate::compare::{Comparator, HashingReader};
+use crate::crypto::{Cipher, EncryptionHeader};
+use crate::journal::Journal;
+use crate::manifest::Manifest;
+use crate::metadata;
+use crate::observer::{notify, BackupObserver};
+use crate::pool::{self, Step};
+use crate::report::{BackupReport, FileReport, Outcome};
+use crate::repository;
+use crate::settings::{Compare, Encryption, Preserve, Settings, SrcFile};
+use crate::snapshot;
+use failure::Error;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// What `apply` does with a file.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "action")]
+pub enum Action {
+    Copy,
+    /// Hard-link the unchanged copy in the previous snapshot.
+    Link {
+        previous: PathBuf,
+    },
+    Skip,
+    Delete,
+    /// Move the backed up file under the trash directory.
+    Trash {
+        target: PathBuf,
+    },
+    /// The file could not be planned; applying reports the error.
+    Fail {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlannedFile {
+    #[serde(flatten)]
+    pub action: Action,
+    pub src: PathBuf,
+    pub dest: Option<PathBuf>,
+    pub bytes: u64,
+    pub entry: SrcFile,
+}
+
+impl PlannedFile {
+    pub fn failed<E: Display>(
+        src: PathBuf,
+        dest: Option<PathBuf>,
+        entry: &SrcFile,
+        error: E,
+    ) -> PlannedFile {
+        PlannedFile {
+            action: Action::Fail {
+                error: error.to_string(),
+            },
+            src,
+            dest,
+            bytes: 0,
+            entry: entry.clone(),
+        }
+    }
+}
+
+/// The files planned for one destination.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DestinationPlan {
+    pub label: String,
+    pub root: Option<PathBuf>,
+    /// Why the destination can't be used. Nothing is applied to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub files: Vec<PlannedFile>,
+    /// Snapshots to remove once the files are applied.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prune: Vec<PathBuf>,
+    /// The snapshot the files go to, when snapshots are enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<PathBuf>,
+    /// Whether completed files are journaled under `root`, so that an
+    /// interrupted `apply` can be resumed by planning again.
+    #[serde(default)]
+    pub journal: bool,
+    /// The archive the files are written to, as `.tar.zst` or `.zip`. The
+    /// destination of each file is its entry, joined to this path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<PathBuf>,
+    /// The snapshot index of the repository the files are stored in as
+    /// chunks. The destination of each file is its entry, joined to this path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<PathBuf>,
+    /// Whether the size and hash of what is written are kept in a manifest
+    /// under `root`, or the snapshot, for `scrub`.
+    #[serde(default)]
+    pub manifest: bool,
+    /// The encryption header planned for a destination that has none yet,
+    /// written to `root` by `apply`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionHeader>,
+}
+
+impl DestinationPlan {
+    /// The bytes `apply` would write.
+    pub fn bytes(&self) -> u64 {
+        self.files
+            .iter()
+            .filter(|file| file.action == Action::Copy)
+            .map(|file| file.bytes)
+            .sum()
+    }
+}
+
+/// How `apply` transforms file contents. The key comes from the settings
+/// the plan is applied with, and is never part of the plan.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Crypt {
+    #[default]
+    Off,
+    /// Copies are encrypted with the key of their destination.
+    Encrypt,
+    /// Sources are backed up files, decrypted with the key of their backup.
+    Decrypt,
+}
+
+impl Crypt {
+    fn is_off(&self) -> bool {
+        *self == Crypt::Off
+    }
+}
+
+/// Everything a backup or restore would do, as returned by `plan`.
+///
+/// Plans can be serialized, reviewed or edited, and applied later with
+/// `apply`. Files are not compared again when applied.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BackupPlan {
+    pub compare: Compare,
+    /// Files applied at once. 0 uses one thread per CPU.
+    #[serde(default = "default_jobs")]
+    pub jobs: usize,
+    #[serde(default)]
+    pub preserve: Preserve,
+    #[serde(default, skip_serializing_if = "Crypt::is_off")]
+    pub crypt: Crypt,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    pub destinations: Vec<DestinationPlan>,
+}
+
+fn default_jobs() -> usize {
+    1
+}
+
+/// Removes the directories above `path` left empty, up to `root`.
+fn remove_empty_parents(path: &Path, root: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// What applying a plan to one destination needs.
+struct Target<'a> {
+    root: &'a Path,
+    comparator: &'a Comparator,
+    journal: Option<&'a Journal>,
+    manifest: Option<&'a Manifest>,
+    preserve: &'a Preserve,
+    crypt: Option<(Crypt, &'a Cipher)>,
+}
+
+impl<'a> Target<'a> {
+    /// Copies `src` and the metadata `preserve` selects next to `dest`,
+    /// flushes it and renames it into place, so that an interrupted copy never
+    /// leaves a partial file at `dest`.
+    fn copy(&self, src: &Path, dest: &Path) -> Result<Outcome, Error> {
+        fs::create_dir_all(dest.parent().unwrap())?;
+        let mut hasher = self.comparator.hasher();
+        atomic::write(dest, |partial| {
+            let mut file = File::create(partial)?;
+            let mut reader =
+                io::BufReader::new(HashingReader::new(File::open(src)?, hasher.as_mut()));
+            match self.crypt {
+                Some((Crypt::Encrypt, cipher)) => {
+                    cipher.encrypt(reader, io::BufWriter::new(&file))?
+                }
+                Some((Crypt::Decrypt, cipher)) => {
+                    cipher.decrypt(reader, io::BufWriter::new(&file))?
+                }
+                _ => {
+                    io::copy(&mut reader, &mut file)?;
+                }
+            }
+            metadata::copy(src, &file, self.preserve)?;
+            Ok(file)
+        })?;
+
+        self.comparator.copied(dest, hasher)?;
+        Ok(Outcome::Copied)
+    }
+
+    fn apply_file(&self, file: &PlannedFile) -> Result<Outcome, Error> {
+        let dest = file
+            .dest
+            .as_ref()
+            .ok_or_else(|| format_err!("no destination planned"))?;
+
+        let outcome = self.apply_action(file, dest)?;
+        if let (Some(journal), Outcome::Copied | Outcome::Linked) = (self.journal, &outcome) {
+            journal.done(&file.src, dest)?;
+        }
+
+        if let Some(manifest) = self.manifest {
+            match outcome {
+                Outcome::Copied | Outcome::Linked => manifest.record(dest)?,
+                Outcome::Skipped => manifest.skipped(dest)?,
+                Outcome::Deleted | Outcome::Trashed => manifest.remove(dest),
+                _ => {}
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    fn apply_action(&self, file: &PlannedFile, dest: &Path) -> Result<Outcome, Error> {
+        match file.action {
+            Action::Copy => self.copy(&file.src, dest),
+            Action::Link { ref previous } => {
+                fs::create_dir_all(dest.parent().unwrap())?;
+                match fs::hard_link(previous, dest) {
+                    Ok(_) => Ok(Outcome::Linked),
+                    Err(_) => self.copy(&file.src, dest),
+                }
+            }
+            Action::Skip => Ok(Outcome::Skipped),
+            Action::Delete => {
+                if dest.is_dir() {
+                    fs::remove_dir(dest)?;
+                } else {
+                    fs::remove_file(dest)?;
+                }
+                remove_empty_parents(dest, self.root);
+                Ok(Outcome::Deleted)
+            }
+            Action::Trash { ref target } => {
+                fs::create_dir_all(target.parent().unwrap())?;
+                fs::rename(dest, target)?;
+                remove_empty_parents(dest, self.root);
+                Ok(Outcome::Trashed)
+            }
+            Action::Fail { .. } => Ok(preview_file(file)),
+        }
+    }
+}
+
+/// The outcome a dry run reports for a planned file, and the outcome of a
+/// file that failed to plan.
+fn preview_file(file: &PlannedFile) -> Outcome {
+    match file.action {
+        Action::Copy => Outcome::WouldCopy,
+        Action::Link { .. } | Action::Skip => Outcome::Skipped,
+        Action::Delete | Action::Trash { .. } => Outcome::WouldDelete,
+        Action::Fail { ref error } => Outcome::Failed {
+            error: error.clone(),
+        },
+    }
+}
+
+/// Loads the key `plan.crypt` needs for the destination at `root`, writing
+/// the `header` planned for it.
+fn load_cipher(
+    crypt: Crypt,
+    root: &Path,
+    header: Option<&EncryptionHeader>,
+    encryption: &Encryption,
+) -> Result<Option<Arc<Cipher>>, Error> {
+    match crypt {
+        Crypt::Off => Ok(None),
+        Crypt::Encrypt => Ok(Some(Arc::new(Cipher::create(root, encryption, header)?))),
+        Crypt::Decrypt => match Cipher::find(root, encryption)? {
+            Some(cipher) => Ok(Some(Arc::new(cipher))),
+            None => Err(format_err!(
+                "backup is not encrypted: {}",
+                root.to_string_lossy()
+            )),
+        },
+    }
+}
+
+/// Applies `plan`, or with `dryrun` only reports what applying it would do.
+/// Keys are read from `encryption`.
+pub(crate) fn execute(
+    plan: BackupPlan,
+    encryption: &Encryption,
+    observer: &mut dyn BackupObserver,
+    dryrun: bool,
+) -> Result<BackupReport, Error> {
+    let mut report = BackupReport {
+        warnings: plan.warnings,
+        ..BackupReport::default()
+    };
+
+    let crypt = plan.crypt;
+    for dest in plan.destinations {
+        let root = match (dest.root, dest.error) {
+            (Some(root), None) => root,
+            (_, error) => {
+                report.add_unavailable(
+                    &dest.label,
+                    error.unwrap_or_else(|| "no root planned".to_owned()),
+                );
+                continue;
+            }
+        };
+
+        let cipher = if dryrun {
+            None
+        } else {
+            match load_cipher(crypt, &root, dest.encryption.as_ref(), encryption) {
+                Ok(cipher) => cipher,
+                Err(e) => {
+                    report.add_unavailable(&dest.label, e.to_string());
+                    continue;
+                }
+            }
+        };
+        let comparator = Comparator::load(&root, plan.compare).encrypted(match crypt {
+            Crypt::Encrypt => cipher.clone(),
+            _ => None,
+        });
+        // Any run can be interrupted, journaled or not, and leave partial
+        // trees, archives or chunks behind.
+        if !dryrun {
+            for path in atomic::clean(&root) {
+                observer.warning(&format!(
+                    "removed a partial file left by an interrupted run: {}",
+                    path.to_string_lossy()
+                ));
+            }
+        }
+        let journal = if dest.journal && !dryrun {
+            let journal = Journal::load(&root);
+            journal.begin(dest.snapshot.as_deref())?;
+            Some(journal)
+        } else {
+            None
+        };
+        // Repositories check their chunks against their names instead.
+        let manifest = match (&dest.archive, &dest.repository) {
+            _ if dryrun || !dest.manifest => None,
+            (None, Some(_)) => None,
+            (Some(_), _) => Some(Manifest::load(&root, None)),
+            (None, None) => Some(match dest.snapshot {
+                Some(ref current) => {
+                    let previous = snapshot::list(&root)
+                        .into_iter()
+                        .map(|(_, path)| path)
+                        .rev()
+                        .find(|path| path != current);
+                    Manifest::load(current, previous.as_deref())
+                }
+                None => Manifest::load(&root, None),
+            }),
+        };
+        let target = Target {
+            root: &root,
+            comparator: &comparator,
+            journal: journal.as_ref(),
+            manifest: manifest.as_ref(),
+            preserve: &plan.preserve,
+            crypt: cipher.as_deref().map(|cipher| (crypt, cipher)),
+        };
+        let mut applied = BackupReport::default();
+
+        let mut sink = |file: &PlannedFile, step| {
+            let outcome = match step {
+                Step::Started => {
+                    if let (Action::Copy | Action::Link { .. } | Action::Skip, Some(dest)) =
+                        (&file.action, &file.dest)
+                    {
+                        observer.file_started(&file.src, dest);
+                    }
+                    return;
+                }
+                Step::Done(outcome) => outcome,
+            };
+
+            let file = FileReport {
+                outcome,
+                src: file.src.clone(),
+                dest: file.dest.clone(),
+                bytes: file.bytes,
+                entry: file.entry.clone(),
+            };
+            notify(observer, &file);
+            applied.push(file);
+        };
+
+        match (&dest.archive, &dest.repository) {
+            (Some(archive), _) if !dryrun => {
+                archive::write(archive, &dest.files, &mut sink)?;
+                if let Some(ref manifest) = manifest {
+                    manifest.record(archive)?;
+                }
+            }
+            (_, Some(index)) if !dryrun => repository::write(index, &dest.files, &mut sink)?,
+            // Files are applied on up to `jobs` threads, but reported in order.
+            _ => pool::for_each_reporting(
+                &dest.files,
+                if dryrun { 1 } else { plan.jobs },
+                |file| {
+                    if dryrun || matches!(file.action, Action::Fail { .. }) {
+                        preview_file(file)
+                    } else {
+                        target.apply_file(file).unwrap_or_else(|e| Outcome::Failed {
+                            error: e.to_string(),
+                        })
+                    }
+                },
+                &mut sink,
+            ),
+        }
+
+        if let Some(manifest) = manifest {
+            manifest.save()?;
+        }
+
+        if !dryrun {
+            comparator.save()?;
+
+            for path in &dest.prune {
+                fs::remove_dir_all(path)?;
+            }
+        }
+
+        if let Some(journal) = journal {
+            journal.finish()?;
+        }
+
+        applied.pruned = dest.prune;
+        report.add_destination(&dest.label, root, applied);
+    }
+
+    Ok(report)
+}
+
+/// Whether `path` is `root` or under it, without going up with `..`.
+fn is_under(path: &Path, root: &Path) -> bool {
+    path.strip_prefix(root).is_ok_and(|rel| {
+        rel.components()
+            .all(|x| matches!(x, Component::Normal(_) | Component::CurDir))
+    })
+}
+
+/// Checks that the root of `dest` is one of `roots`, those of the
+/// destinations in the settings, and that everything it writes, links from or
+/// removes is under that root, since a plan read back from a file may have
+/// been edited.
+fn check_paths(dest: &DestinationPlan, roots: &[PathBuf]) -> Result<(), Error> {
+    let root = match (&dest.root, &dest.error) {
+        (Some(root), None) => root,
+        _ => return Ok(()),
+    };
+
+    if !roots.contains(root) {
+        return Err(format_err!(
+            "plan for {} writes to {}, which is not a destination in the settings",
+            dest.label,
+            root.to_string_lossy()
+        ));
+    }
+
+    let files = dest.files.iter().flat_map(|file| {
+        let other = match file.action {
+            Action::Link { ref previous } => Some(previous),
+            Action::Trash { ref target } => Some(target),
+            _ => None,
+        };
+        match file.action {
+            Action::Fail { .. } => None,
+            _ => file.dest.as_ref(),
+        }
+        .into_iter()
+        .chain(other)
+    });
+    let paths = files
+        .chain(&dest.prune)
+        .chain(&dest.snapshot)
+        .chain(&dest.archive)
+        .chain(&dest.repository);
+
+    for path in paths {
+        if !is_under(path, root) {
+            return Err(format_err!(
+                "plan for {} reaches outside {}: {}",
+                dest.label,
+                root.to_string_lossy(),
+                path.to_string_lossy()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies, links and removes the files in `plan`, with the keys `settings`
+/// gives if it encrypts or decrypts. Nothing is applied if the plan reaches
+/// outside the destinations of `settings`.
+pub fn apply(
+    plan: BackupPlan,
+    settings: &Settings,
+    observer: &mut dyn BackupObserver,
+) -> Result<BackupReport, Error> {
+    let roots: Vec<PathBuf> = crate::destinations(settings.dest.as_slice())
+        .into_iter()
+        .filter_map(|(_, root)| root.ok())
+        .collect();
+    for dest in &plan.destinations {
+        check_paths(dest, &roots)?;
+    }
+
+    execute(plan, &settings.encryption, observer, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    /// Plans copying `src/a` to `out/copy/a`.
+    fn planned(dir: &TempDir) -> (BackupPlan, Settings) {
+        dir.write("src/a", "a");
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        );
+        (crate::plan(&settings).unwrap(), settings)
+    }
+
+    #[test]
+    fn plans_apply() {
+        let dir = TempDir::new("plan-apply");
+        let (plan, settings) = planned(&dir);
+        assert_eq!(plan.destinations[0].root, Some(dir.join("out")));
+        assert!(!dir.join("out/copy").exists());
+
+        // As `ubackup plan -o` writes it and `ubackup apply` reads it back.
+        let plan: BackupPlan =
+            serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+
+        let report = apply(plan, &settings, &mut ()).unwrap();
+        assert_eq!(report.copies, 1);
+        assert_eq!(dir.read("out/copy/a").as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn partial_files_are_removed_by_every_run() {
+        let dir = TempDir::new("plan-partial");
+        let (plan, settings) = planned(&dir);
+        let partial = dir.write("out/copy/.ubackup-part-b", "half");
+
+        apply(plan, &settings, &mut ()).unwrap();
+        assert!(!partial.exists());
+        assert_eq!(dir.read("out/copy/a").as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn tampered_plans_are_refused() {
+        let dir = TempDir::new("plan-tampered");
+        let (plan, settings) = planned(&dir);
+
+        for dest in [dir.join("victim"), dir.join("out/../victim")] {
+            let mut tampered = plan.clone();
+            tampered.destinations[0].files[0].dest = Some(dest);
+            assert!(apply(tampered, &settings, &mut ()).is_err());
+        }
+
+        let mut tampered = plan.clone();
+        tampered.destinations[0].prune = vec![dir.join("src")];
+        assert!(apply(tampered, &settings, &mut ()).is_err());
+
+        // A root of its own, with everything under it.
+        let mut tampered = plan;
+        tampered.destinations[0].root = Some(dir.path.clone());
+        tampered.destinations[0].files[0].dest = Some(dir.join("victim"));
+        assert!(apply(tampered, &settings, &mut ()).is_err());
+
+        assert!(dir.join("src").exists());
+        assert!(!dir.join("victim").exists());
+        assert!(!dir.join("out/copy").exists());
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// The number of workers `jobs` asks for, 0 meaning one per CPU.
+fn workers(jobs: usize) -> usize {
+    match jobs {
+        0 => thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        jobs => jobs,
+    }
+}
+
+/// What happened to an item passed to `for_each_reporting`.
+pub(crate) enum Step<R> {
+    /// A worker picked the item up and is about to call `f` on it.
+    Started,
+    Done(R),
+}
+
+/// Calls `f` on every item from up to `jobs` threads, and passes the results
+/// to `sink` on the calling thread in the order of `items`.
+pub(crate) fn for_each_ordered<T, R, F, S>(items: &[T], jobs: usize, f: F, mut sink: S)
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    S: FnMut(&T, R),
+{
+    for_each_reporting(items, jobs, f, |item, step| {
+        if let Step::Done(result) = step {
+            sink(item, result);
+        }
+    });
+}
+
+/// Like `for_each_ordered`, but also tells `sink` when each item is started.
+/// Starts are passed as workers pick items up, so they can arrive out of
+/// order, but always before the result of the same item.
+pub(crate) fn for_each_reporting<T, R, F, S>(items: &[T], jobs: usize, f: F, mut sink: S)
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    S: FnMut(&T, Step<R>),
+{
+    let workers = workers(jobs).min(items.len());
+    if workers <= 1 {
+        for item in items {
+            sink(item, Step::Started);
+            sink(item, Step::Done(f(item)));
+        }
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let next = &next;
+            let f = &f;
+
+            // Each worker sends the start of an item before its result, and
+            // the channel keeps the order of one sender.
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= items.len()
+                    || tx.send((i, Step::Started)).is_err()
+                    || tx.send((i, Step::Done(f(&items[i])))).is_err()
+                {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+
+        for (i, step) in rx {
+            match step {
+                Step::Started => sink(&items[i], Step::Started),
+                Step::Done(result) => {
+                    pending.insert(i, result);
+                    while let Some(result) = pending.remove(&expected) {
+                        sink(&items[expected], Step::Done(result));
+                        expected += 1;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn results_keep_the_order_of_items() {
+        let items: Vec<u64> = (0..32).collect();
+        for &jobs in &[0, 1, 4] {
+            let mut results = vec![];
+            for_each_ordered(
+                &items,
+                jobs,
+                |&i| {
+                    // Make the first items the slowest to finish.
+                    thread::sleep(Duration::from_millis(32 - i));
+                    i * 2
+                },
+                |&i, result| results.push((i, result)),
+            );
+            assert_eq!(
+                results,
+                items.iter().map(|&i| (i, i * 2)).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn no_items_call_nothing() {
+        let items: Vec<u64> = vec![];
+        for_each_ordered(&items, 4, |_| unreachable!(), |_, ()| unreachable!());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::settings::SrcFile;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "outcome")]
+pub enum Outcome {
+    Copied,
+    Skipped,
+    Linked,
+    WouldCopy,
+    Deleted,
+    Trashed,
+    WouldDelete,
+    Failed { error: String },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FileReport {
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    pub src: PathBuf,
+    pub dest: Option<PathBuf>,
+    pub bytes: u64,
+    pub entry: SrcFile,
+}
+
+/// The outcome of a backup for one destination.
+#[derive(Debug, Serialize, Clone)]
+pub struct DestinationReport {
+    pub label: String,
+    pub root: Option<PathBuf>,
+    pub successes: u32,
+    pub errors: u32,
+    pub copies: u32,
+    pub skips: u32,
+    pub deletes: u32,
+    /// Why the destination could not be used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Clone)]
+pub struct BackupReport {
+    pub successes: u32,
+    pub errors: u32,
+    pub copies: u32,
+    pub skips: u32,
+    pub deletes: u32,
+    pub files: Vec<FileReport>,
+    pub pruned: Vec<PathBuf>,
+    pub destinations: Vec<DestinationReport>,
+    pub warnings: Vec<String>,
+}
+
+impl FileReport {
+    pub fn failed<E: Display>(
+        src: PathBuf,
+        dest: Option<PathBuf>,
+        entry: &SrcFile,
+        error: E,
+    ) -> FileReport {
+        FileReport {
+            outcome: Outcome::Failed {
+                error: error.to_string(),
+            },
+            src,
+            dest,
+            bytes: 0,
+            entry: entry.clone(),
+        }
+    }
+}
+
+impl BackupReport {
+    pub fn push(&mut self, file: FileReport) {
+        match file.outcome {
+            Outcome::Copied | Outcome::WouldCopy => {
+                self.successes += 1;
+                self.copies += 1;
+            }
+            Outcome::Skipped | Outcome::Linked => {
+                self.successes += 1;
+                self.skips += 1;
+            }
+            Outcome::Deleted | Outcome::Trashed | Outcome::WouldDelete => {
+                self.successes += 1;
+                self.deletes += 1;
+            }
+            Outcome::Failed { .. } => self.errors += 1,
+        }
+
+        self.files.push(file);
+    }
+
+    /// Adds the report of a backup to `root`, the destination `label` selected.
+    pub(crate) fn add_destination(&mut self, label: &str, root: PathBuf, report: BackupReport) {
+        self.destinations.push(DestinationReport {
+            label: label.to_owned(),
+            root: Some(root),
+            successes: report.successes,
+            errors: report.errors,
+            copies: report.copies,
+            skips: report.skips,
+            deletes: report.deletes,
+            error: None,
+        });
+
+        self.successes += report.successes;
+        self.errors += report.errors;
+        self.copies += report.copies;
+        self.skips += report.skips;
+        self.deletes += report.deletes;
+        self.files.extend(report.files);
+        self.pruned.extend(report.pruned);
+        self.warnings.extend(report.warnings);
+    }
+
+    /// Records a destination that could not be used, as one error.
+    pub(crate) fn add_unavailable<E: Display>(&mut self, label: &str, error: E) {
+        self.destinations.push(DestinationReport {
+            label: label.to_owned(),
+            root: None,
+            successes: 0,
+            errors: 1,
+            copies: 0,
+            skips: 0,
+            deletes: 0,
+            error: Some(error.to_string()),
+        });
+
+        self.errors += 1;
+    }
+}
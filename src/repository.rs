@@ -0,0 +1,630 @@
+use crate::archive::{self, ArchiveEntry};
+use crate::atomic;
+use crate::plan::{Action, PlannedFile};
+use crate::pool::Step;
+use crate::report::Outcome;
+use failure::Error;
+use fastcdc::v2020::StreamCDC;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, FileTimes};
+use std::io::{BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+const CHUNKS_DIR: &str = "chunks";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const INDEX_EXTENSION: &str = "json";
+
+// Sizes of content-defined chunks, in bytes.
+const MIN_CHUNK: u32 = 16 * 1024;
+const AVG_CHUNK: u32 = 64 * 1024;
+const MAX_CHUNK: u32 = 256 * 1024;
+
+/// The files of one run, as the chunks of their contents.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    files: Vec<IndexFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexFile {
+    /// The entry of the file, relative to the snapshot, joined with `/`.
+    path: String,
+    bytes: u64,
+    mtime: (u64, u32),
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    /// BLAKE3 hashes of the chunks, in order.
+    chunks: Vec<String>,
+}
+
+/// The index a run started at `name` writes to the repository at `root`.
+/// Names have a one-second resolution, so when another run started in the
+/// same second the name gets a `-2`, `-3`... suffix.
+pub(crate) fn index_path(root: &Path, name: &str) -> PathBuf {
+    let dir = root.join(SNAPSHOTS_DIR);
+    let path = dir.join(format!("{}.{}", name, INDEX_EXTENSION));
+    if !path.exists() {
+        return path;
+    }
+
+    (2..)
+        .map(|n| dir.join(format!("{}-{}.{}", name, n, INDEX_EXTENSION)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// The name of the run an index was written for and its suffix, to sort
+/// indexes in the order they were written.
+fn index_order(path: &Path) -> (String, u32) {
+    let stem = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    match stem.rsplit_once('-') {
+        Some((name, n)) if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) => {
+            (name.to_owned(), n.parse().unwrap_or(u32::MAX))
+        }
+        _ => (stem, 1),
+    }
+}
+
+/// Whether `path` is a snapshot index in a repository.
+pub(crate) fn is_index(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x == INDEX_EXTENSION)
+        && path
+            .parent()
+            .and_then(Path::file_name)
+            .is_some_and(|x| x == SNAPSHOTS_DIR)
+}
+
+/// The latest snapshot index in the repository at `root`. Indexes are named
+/// after the time their run started, with a suffix for later runs started in
+/// the same second.
+pub(crate) fn latest(root: &Path) -> Option<PathBuf> {
+    let mut indexes: Vec<PathBuf> = fs::read_dir(root.join(SNAPSHOTS_DIR))
+        .map(|entries| {
+            entries
+                .filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| x.is_file() && is_index(x))
+                .collect()
+        })
+        .unwrap_or_default();
+    indexes.sort_by_key(|x| index_order(x));
+    indexes.pop()
+}
+
+/// Whether `dir` is the root of a repository.
+pub(crate) fn is_repository(dir: &Path) -> bool {
+    dir.join(CHUNKS_DIR).is_dir() && dir.join(SNAPSHOTS_DIR).is_dir()
+}
+
+/// Whether `dir` is one of the directories a repository keeps its chunks and
+/// indexes in, under its root.
+pub(crate) fn is_internal(dir: &Path) -> bool {
+    dir.parent().is_some_and(is_repository)
+        && dir
+            .file_name()
+            .is_some_and(|x| x == CHUNKS_DIR || x == SNAPSHOTS_DIR)
+}
+
+/// Every chunk the snapshot indexes of the repository at `root` refer to,
+/// with its hash.
+pub(crate) fn chunks(root: &Path) -> Result<Vec<(PathBuf, blake3::Hash)>, Error> {
+    let mut hashes = BTreeSet::new();
+    for entry in fs::read_dir(root.join(SNAPSHOTS_DIR))? {
+        let path = entry?.path();
+        if !is_index(&path) {
+            continue;
+        }
+
+        for file in load(&path)?.files {
+            for hash in file.chunks {
+                let hash = blake3::Hash::from_hex(&hash)
+                    .map_err(|_| format_err!("invalid chunk hash in index: {}", hash))?;
+                hashes.insert(*hash.as_bytes());
+            }
+        }
+    }
+
+    Ok(hashes
+        .into_iter()
+        .map(|x| {
+            let hash = blake3::Hash::from(x);
+            (chunk_path(root, &hash.to_hex()), hash)
+        })
+        .collect())
+}
+
+/// The repository an index belongs to.
+fn repository_root(index: &Path) -> &Path {
+    index.parent().and_then(Path::parent).unwrap()
+}
+
+fn chunk_path(root: &Path, hash: &str) -> PathBuf {
+    root.join(CHUNKS_DIR).join(&hash[..2]).join(hash)
+}
+
+fn load(index: &Path) -> Result<Index, Error> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(index)?))?)
+}
+
+fn stamp(md: &fs::Metadata) -> (u64, u32) {
+    md.modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| (x.as_secs(), x.subsec_nanos()))
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn mode(md: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(md.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn mode(_md: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Splits `src` into chunks, storing the ones the repository at `root`
+/// doesn't have yet, or only has a damaged copy of.
+///
+/// Chunks are never removed: indexes are only ever added, and the chunks an
+/// interrupted run stored before writing its index are reused by the next.
+/// Removing an index by hand leaves its chunks behind.
+fn store(root: &Path, src: &Path) -> Result<Vec<String>, Error> {
+    let mut chunks = vec![];
+
+    let reader = BufReader::new(File::open(src)?);
+    for chunk in StreamCDC::new(reader, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+        let chunk = chunk?;
+        let hash = blake3::hash(&chunk.data);
+
+        // A chunk already stored is only reused if it is intact, since a
+        // damaged one would break every file that shares it.
+        let path = chunk_path(root, &hash.to_hex());
+        if !fs::read(&path).is_ok_and(|data| blake3::hash(&data) == hash) {
+            fs::create_dir_all(path.parent().unwrap())?;
+            atomic::write_file(&path, &chunk.data)?;
+        }
+        chunks.push(hash.to_hex().to_string());
+    }
+
+    Ok(chunks)
+}
+
+/// The files of the latest index in the repository at `root`, by entry.
+fn latest_files(root: &Path) -> Result<HashMap<String, IndexFile>, Error> {
+    Ok(match latest(root) {
+        Some(path) => load(&path)?
+            .files
+            .into_iter()
+            .map(|x| (x.path.clone(), x))
+            .collect(),
+        None => HashMap::new(),
+    })
+}
+
+/// Whether `previous` was stored from a source with the size and mtime of
+/// `md`, so that its chunks can be reused.
+fn unchanged(previous: Option<&IndexFile>, md: &fs::Metadata) -> bool {
+    previous.is_some_and(|x| x.bytes == md.len() && x.mtime == stamp(md))
+}
+
+/// Plans the files whose source has the size and mtime of their entry in the
+/// latest index as links to it, since `write` reuses their chunks.
+pub(crate) fn link_unchanged(index: &Path, files: &mut [PlannedFile]) -> Result<(), Error> {
+    let root = repository_root(index);
+    let previous = match latest(root) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let entries = latest_files(root)?;
+
+    for file in files.iter_mut() {
+        if let (Action::Copy, Some(dest)) = (&file.action, &file.dest) {
+            let path = archive::entry_name(dest.strip_prefix(index).unwrap_or(dest));
+            if file
+                .src
+                .metadata()
+                .is_ok_and(|md| unchanged(entries.get(&path), &md))
+            {
+                file.action = Action::Link {
+                    previous: previous.join(&path),
+                };
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the files `Copy` and `Link` plan for as chunks in the repository
+/// `index` belongs to, reporting each to `sink`, then writes `index`, or the
+/// next free name if another run wrote it meanwhile. Files whose size and
+/// mtime still match the latest index reuse its chunks without being read,
+/// and are reported as linked.
+pub(crate) fn write<F>(index: &Path, files: &[PlannedFile], mut sink: F) -> Result<(), Error>
+where
+    F: FnMut(&PlannedFile, Step<Outcome>),
+{
+    let root = repository_root(index);
+    let previous = latest_files(root)?;
+
+    let mut written = Index::default();
+    for file in files {
+        sink(file, Step::Started);
+        let outcome = match (&file.action, &file.dest) {
+            (Action::Copy | Action::Link { .. }, Some(dest)) => {
+                let path = archive::entry_name(dest.strip_prefix(index).unwrap_or(dest));
+                let stored =
+                    file.src.metadata().map_err(Error::from).and_then(|md| {
+                        match previous.get(&path) {
+                            Some(x) if unchanged(Some(x), &md) => {
+                                Ok((x.chunks.clone(), md, Outcome::Linked))
+                            }
+                            _ => Ok((store(root, &file.src)?, md, Outcome::Copied)),
+                        }
+                    });
+
+                match stored {
+                    Ok((chunks, md, outcome)) => {
+                        written.files.push(IndexFile {
+                            path,
+                            bytes: md.len(),
+                            mtime: stamp(&md),
+                            mode: mode(&md),
+                            chunks,
+                        });
+                        outcome
+                    }
+                    Err(e) => Outcome::Failed {
+                        error: e.to_string(),
+                    },
+                }
+            }
+            (Action::Fail { error }, _) => Outcome::Failed {
+                error: error.clone(),
+            },
+            _ => Outcome::Failed {
+                error: "only copies and links can be stored in a repository".to_owned(),
+            },
+        };
+        sink(file, Step::Done(outcome));
+    }
+
+    // The index is written last, so an interrupted run leaves only chunks.
+    fs::create_dir_all(index.parent().unwrap())?;
+    let target = if index.exists() {
+        index_path(root, &index_order(index).0)
+    } else {
+        index.to_owned()
+    };
+    atomic::write_file(&target, &serde_json::to_vec(&written)?)
+}
+
+/// Lists the files in the snapshot `index`.
+pub(crate) fn list(index: &Path) -> Result<Vec<ArchiveEntry>, Error> {
+    Ok(load(index)?
+        .files
+        .into_iter()
+        .map(|x| ArchiveEntry {
+            path: PathBuf::from(x.path),
+            bytes: x.bytes,
+            error: None,
+        })
+        .collect())
+}
+
+/// The path of an entry under the directory it is extracted to, if it
+/// stays inside it.
+fn enclosed(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.components().all(|x| matches!(x, Component::Normal(_))) {
+        Some(path.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Writes the file `entry` of a snapshot to `dest`, from the chunks under
+/// `root`, checking each against its hash.
+fn extract_file(root: &Path, entry: &IndexFile, dest: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dest.parent().unwrap())?;
+    let mut file = File::create(dest)?;
+    for hash in &entry.chunks {
+        let hash = blake3::Hash::from_hex(hash)
+            .map_err(|_| format_err!("invalid chunk hash in index: {}", hash))?;
+        let path = chunk_path(root, &hash.to_hex());
+        let data = fs::read(&path)?;
+        if blake3::hash(&data) != hash {
+            return Err(format_err!("chunk is corrupt: {}", path.to_string_lossy()));
+        }
+        file.write_all(&data)?;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = entry.mode {
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+    }
+
+    let time = UNIX_EPOCH + Duration::new(entry.mtime.0, entry.mtime.1);
+    file.set_times(FileTimes::new().set_accessed(time).set_modified(time))?;
+    Ok(())
+}
+
+/// Rebuilds the files in the snapshot `index` under `to`. Entries that would
+/// land outside `to` are skipped, and a file that can't be rebuilt, as when
+/// one of its chunks is corrupt, is removed and returned with the error.
+pub(crate) fn extract(index: &Path, to: &Path) -> Result<Vec<ArchiveEntry>, Error> {
+    let root = repository_root(index);
+    let mut ret = vec![];
+
+    for entry in load(index)?.files {
+        let rel = match enclosed(&entry.path) {
+            Some(rel) => rel,
+            None => continue,
+        };
+
+        let dest = to.join(&rel);
+        let error = match extract_file(root, &entry, &dest) {
+            Ok(()) => None,
+            Err(e) => {
+                let _ = fs::remove_file(&dest);
+                Some(e.to_string())
+            }
+        };
+
+        ret.push(ArchiveEntry {
+            path: rel,
+            bytes: entry.bytes,
+            error,
+        });
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SrcFile;
+    use crate::testing::TempDir;
+
+    const RUN: &str = "2024-03-09T140500";
+
+    /// Contents that don't repeat within a chunk, so they split in several.
+    fn contents(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2_654_435_761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn copies(src: &Path, index: &Path, names: &[&str]) -> Vec<PlannedFile> {
+        names
+            .iter()
+            .map(|name| PlannedFile {
+                action: Action::Copy,
+                src: src.join(name),
+                dest: Some(index.join("files").join(name)),
+                bytes: 0,
+                entry: SrcFile::default(),
+            })
+            .collect()
+    }
+
+    fn write_all(index: &Path, files: &[PlannedFile]) -> Vec<Outcome> {
+        let mut outcomes = vec![];
+        write(index, files, |_, step| {
+            if let Step::Done(outcome) = step {
+                outcomes.push(outcome);
+            }
+        })
+        .unwrap();
+        outcomes
+    }
+
+    #[test]
+    fn write_and_extract() {
+        let dir = TempDir::new("repository-extract");
+        let (src, root, to) = (dir.join("src"), dir.join("repo"), dir.join("to"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        let big = contents(1024 * 1024, 1);
+        fs::write(src.join("big"), &big).unwrap();
+        fs::write(src.join("sub/small"), b"small").unwrap();
+        fs::write(src.join("empty"), b"").unwrap();
+
+        let index = index_path(&root, RUN);
+        let files = copies(&src, &index, &["big", "sub/small", "empty"]);
+        assert_eq!(write_all(&index, &files), vec![Outcome::Copied; 3]);
+        assert!(is_repository(&root) && is_index(&index));
+        assert_eq!(latest(&root), Some(index.clone()));
+
+        let listed: Vec<_> = list(&index).unwrap().into_iter().map(|x| x.path).collect();
+        assert_eq!(
+            listed,
+            vec![
+                PathBuf::from("files/big"),
+                PathBuf::from("files/sub/small"),
+                PathBuf::from("files/empty")
+            ]
+        );
+
+        extract(&index, &to).unwrap();
+        assert_eq!(fs::read(to.join("files/big")).unwrap(), big);
+        assert_eq!(fs::read(to.join("files/sub/small")).unwrap(), b"small");
+        assert_eq!(fs::read(to.join("files/empty")).unwrap(), b"");
+        assert_eq!(
+            to.join("files/big").metadata().unwrap().modified().unwrap(),
+            src.join("big").metadata().unwrap().modified().unwrap()
+        );
+    }
+
+    #[test]
+    fn chunks_are_shared() {
+        let dir = TempDir::new("repository-dedup");
+        let (src, root) = (dir.join("src"), dir.join("repo"));
+        fs::create_dir_all(&src).unwrap();
+        let mut data = contents(1024 * 1024, 2);
+        fs::write(src.join("a"), &data).unwrap();
+        data.extend(b"appended");
+        fs::write(src.join("b"), &data).unwrap();
+
+        let index = index_path(&root, RUN);
+        write_all(&index, &copies(&src, &index, &["a", "b"]));
+
+        let files = load(&index).unwrap().files;
+        let stored = chunks(&root).unwrap();
+        assert!(files[0].chunks.len() > 1);
+        assert_eq!(stored.len(), files[0].chunks.len() + 1);
+        assert!(stored.iter().all(|(path, _)| path.is_file()));
+    }
+
+    #[test]
+    fn unchanged_files_reuse_chunks() {
+        let dir = TempDir::new("repository-unchanged");
+        let (src, root) = (dir.join("src"), dir.join("repo"));
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("same"), b"same").unwrap();
+        fs::write(src.join("changed"), b"before").unwrap();
+
+        let first = index_path(&root, RUN);
+        write_all(&first, &copies(&src, &first, &["same", "changed"]));
+        fs::write(src.join("changed"), b"after, and longer").unwrap();
+
+        let second = index_path(&root, "2024-03-10T140500");
+        let mut files = copies(&src, &second, &["same", "changed"]);
+        link_unchanged(&second, &mut files).unwrap();
+        assert_eq!(
+            files[0].action,
+            Action::Link {
+                previous: first.join("files/same")
+            }
+        );
+        assert_eq!(files[1].action, Action::Copy);
+
+        assert_eq!(
+            write_all(&second, &files),
+            vec![Outcome::Linked, Outcome::Copied]
+        );
+        assert_eq!(latest(&root), Some(second));
+    }
+
+    #[test]
+    fn same_second_indexes() {
+        let dir = TempDir::new("repository-same-second");
+        let (src, root) = (dir.join("src"), dir.join("repo"));
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a"), b"a").unwrap();
+
+        // Two runs planned in the same second, then a third.
+        let first = index_path(&root, RUN);
+        let second = index_path(&root, RUN);
+        assert_eq!(first, second);
+        write_all(&first, &copies(&src, &first, &["a"]));
+        write_all(&second, &copies(&src, &second, &["a"]));
+
+        let third = index_path(&root, RUN);
+        assert_eq!(third.file_name().unwrap(), "2024-03-09T140500-3.json");
+        write_all(&third, &copies(&src, &third, &["a"]));
+
+        let mut written: Vec<_> = fs::read_dir(root.join(SNAPSHOTS_DIR))
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        written.sort();
+        assert_eq!(
+            written,
+            vec![
+                "2024-03-09T140500-2.json",
+                "2024-03-09T140500-3.json",
+                "2024-03-09T140500.json"
+            ]
+        );
+        assert_eq!(latest(&root), Some(third));
+    }
+
+    #[test]
+    fn indexes_sort_in_the_order_written() {
+        let mut indexes: Vec<PathBuf> = [
+            "2024-03-09T140500-10.json",
+            "2024-03-09T140501.json",
+            "2024-03-09T140500-2.json",
+            "2024-03-09T140500.json",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        indexes.sort_by_key(|x| index_order(x));
+
+        assert_eq!(
+            indexes,
+            vec![
+                PathBuf::from("2024-03-09T140500.json"),
+                PathBuf::from("2024-03-09T140500-2.json"),
+                PathBuf::from("2024-03-09T140500-10.json"),
+                PathBuf::from("2024-03-09T140501.json"),
+            ]
+        );
+    }
+
+    #[test]
+    fn corrupt_chunks_fail_their_file_only() {
+        let dir = TempDir::new("repository-corrupt");
+        let (src, root, to) = (dir.join("src"), dir.join("repo"), dir.join("to"));
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a"), b"contents").unwrap();
+        fs::write(src.join("b"), b"other contents").unwrap();
+
+        let index = index_path(&root, RUN);
+        write_all(&index, &copies(&src, &index, &["a", "b"]));
+        let chunk = chunk_path(&root, &blake3::hash(b"contents").to_hex());
+        fs::write(chunk, b"corrupt").unwrap();
+
+        let extracted = extract(&index, &to).unwrap();
+        assert_eq!(extracted.len(), 2);
+        assert!(extracted[0].error.as_ref().unwrap().contains("corrupt"));
+        assert_eq!(extracted[1].error, None);
+        assert!(!to.join("files/a").exists());
+        assert_eq!(fs::read(to.join("files/b")).unwrap(), b"other contents");
+    }
+
+    #[test]
+    fn corrupt_chunks_are_stored_again() {
+        let dir = TempDir::new("repository-repair");
+        let (src, root) = (dir.join("src"), dir.join("repo"));
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a"), b"contents").unwrap();
+        fs::write(src.join("b"), b"contents").unwrap();
+
+        let index = index_path(&root, RUN);
+        write_all(&index, &copies(&src, &index, &["a"]));
+        let chunk = chunk_path(&root, &blake3::hash(b"contents").to_hex());
+        fs::write(&chunk, b"corrupt").unwrap();
+
+        let index = index_path(&root, "2024-03-10T090000");
+        write_all(&index, &copies(&src, &index, &["b"]));
+        assert_eq!(fs::read(&chunk).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn entries_stay_inside() {
+        assert_eq!(enclosed("a/b"), Some(PathBuf::from("a/b")));
+        assert_eq!(enclosed("../a"), None);
+        assert_eq!(enclosed("a/../../b"), None);
+        assert_eq!(enclosed("/etc/passwd"), None);
+    }
+}
@@ -0,0 +1,647 @@
+use crate::archive;
+use crate::atomic;
+use crate::compare::Comparator;
+use crate::crypto::{Cipher, Names};
+use crate::ignore::Ignore;
+use crate::observer::BackupObserver;
+use crate::plan::{self, BackupPlan, Crypt, DestinationPlan, PlannedFile};
+use crate::report::BackupReport;
+use crate::repository;
+use crate::settings::{Archive, Compare, Match, Settings, SrcFile};
+use crate::template::Template;
+use crate::{
+    brace_filter, capture_names, capture_pattern, component_regex, destinations, filter_queue,
+    glob_entry, is_capture, is_wildcard, passes_filters, passes_recursive_filter, plan_copy,
+    snapshot, template, Run, RESERVED_PREFIX,
+};
+use chrono::Local;
+use failure::Error;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::sync::Arc;
+
+#[derive(Debug, Default, Clone)]
+pub struct RestoreOptions {
+    /// Backup tree, archive or repository snapshot index to restore from.
+    /// Defaults to where `backup` writes, or the latest snapshot, archive or
+    /// repository snapshot there.
+    pub from: Option<PathBuf>,
+    /// Restore under this directory instead of the original source paths.
+    pub root: Option<PathBuf>,
+    /// Leave files alone that are newer than their backed up copy.
+    pub keep_newer: bool,
+}
+
+/// A path in the backup tree matched by a `to` template.
+#[derive(Debug)]
+pub(crate) struct BackupMatch {
+    pub path: PathBuf,
+    pub captures: BTreeMap<usize, String>,
+}
+
+/// The kind of each capture in `from`, by index: `true` for `**`.
+fn capture_kinds(from: &str) -> BTreeMap<usize, bool> {
+    let mut kinds = BTreeMap::new();
+
+    for component in Path::new(from).components() {
+        if let Component::Normal(part) = component {
+            let part = part.to_string_lossy();
+            if is_capture(&part) {
+                kinds.insert(kinds.len() + 1, capture_pattern(&part) == "**");
+            }
+        }
+    }
+
+    kinds
+}
+
+/// `path`, relative to the sources, as it is named in the backup tree.
+fn backed_up(path: &str, cipher: Option<&Cipher>) -> Option<PathBuf> {
+    match cipher {
+        Some(cipher) => Path::new(path)
+            .components()
+            .map(|x| cipher.encrypt_name(&x.as_os_str().to_string_lossy()).ok())
+            .collect(),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+/// The source name of the backup tree entry `name`, if it wasn't written by
+/// ubackup for itself.
+fn source_name(name: &str, cipher: Option<&Cipher>) -> Option<String> {
+    if name.starts_with(RESERVED_PREFIX) {
+        return None;
+    }
+
+    match cipher {
+        Some(cipher) => cipher.decrypt_name(name),
+        None => Some(name.to_owned()),
+    }
+}
+
+fn unglob_parts(
+    current: &Path,
+    parts: &[Template],
+    recursive: &BTreeMap<usize, bool>,
+    captures: &BTreeMap<usize, String>,
+    cipher: Option<&Cipher>,
+    found: &mut Vec<BackupMatch>,
+) {
+    let (part, rest) = match parts.split_first() {
+        Some(v) => v,
+        None => {
+            found.push(BackupMatch {
+                path: current.to_owned(),
+                captures: captures.clone(),
+            });
+            return;
+        }
+    };
+
+    let known = match (part.literal(), part.capture()) {
+        (Some(text), _) => Some(text),
+        (None, Some(index)) => captures.get(&index).cloned(),
+        (None, None) => {
+            return unglob_template(current, part, rest, recursive, captures, cipher, found)
+        }
+    };
+
+    if let Some(known) = known {
+        if let Some(next) = backed_up(&known, cipher).map(|x| current.join(x)) {
+            if next.exists() {
+                unglob_parts(&next, rest, recursive, captures, cipher, found);
+            }
+        }
+        return;
+    }
+
+    let index = part.capture().unwrap();
+    let is_recursive = recursive.get(&index).cloned().unwrap_or(false);
+    let mut dirs = vec![(current.to_owned(), PathBuf::new())];
+
+    if is_recursive {
+        let mut captures = captures.clone();
+        captures.insert(index, String::new());
+        unglob_parts(current, rest, recursive, &captures, cipher, found);
+    }
+
+    while let Some((dir, rel)) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(|x| x.ok()) {
+            let name = match source_name(&entry.file_name().to_string_lossy(), cipher) {
+                Some(name) => name,
+                None => continue,
+            };
+            let is_dir = entry.file_type().map(|x| x.is_dir()).unwrap_or(false);
+            if (is_recursive || !rest.is_empty()) && !is_dir {
+                continue;
+            }
+
+            let rel = rel.join(&name);
+            let mut captures = captures.clone();
+            captures.insert(index, rel.to_string_lossy().into_owned());
+            unglob_parts(&entry.path(), rest, recursive, &captures, cipher, found);
+
+            if is_recursive {
+                dirs.push((entry.path(), rel));
+            }
+        }
+    }
+}
+
+/// Matches a component mixing text, variables and captures against the
+/// entries of `current`.
+fn unglob_template(
+    current: &Path,
+    part: &Template,
+    rest: &[Template],
+    recursive: &BTreeMap<usize, bool>,
+    captures: &BTreeMap<usize, String>,
+    cipher: Option<&Cipher>,
+    found: &mut Vec<BackupMatch>,
+) {
+    let (re, groups) = match part.regex(captures) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let entries = match fs::read_dir(current) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|x| x.ok()) {
+        let name = match source_name(&entry.file_name().to_string_lossy(), cipher) {
+            Some(name) => name,
+            None => continue,
+        };
+        let is_dir = entry.file_type().map(|x| x.is_dir()).unwrap_or(false);
+        if !rest.is_empty() && !is_dir {
+            continue;
+        }
+
+        let caps = match re.captures(&name) {
+            Some(caps) => caps,
+            None => continue,
+        };
+
+        let mut captures = captures.clone();
+        let consistent = groups.iter().enumerate().all(|(i, index)| {
+            let value = &caps[i + 1];
+            captures.entry(*index).or_insert_with(|| value.to_owned()) == value
+        });
+
+        if consistent {
+            unglob_parts(&entry.path(), rest, recursive, &captures, cipher, found);
+        }
+    }
+}
+
+/// Finds every path under `root` that the `to` template of `entry` could
+/// have produced from captures that pass its filters.
+///
+/// When a `**` capture makes several splits of the same path possible, only
+/// the outermost match is kept, since copying it covers the rest. Names in
+/// the backup tree are decrypted with `cipher` if they are encrypted.
+pub(crate) fn unglob(
+    root: &Path,
+    entry: &SrcFile,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<BackupMatch>, Error> {
+    let mut found = vec![];
+    unglob_parts(
+        root,
+        &template::parse_path(&entry.to, &capture_names(&entry.from))?,
+        &capture_kinds(&entry.from),
+        &BTreeMap::new(),
+        cipher,
+        &mut found,
+    );
+
+    found.retain(|x| captures_allowed(entry, &x.captures));
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut ret: Vec<BackupMatch> = vec![];
+    for x in found {
+        if !ret.iter().any(|y| x.path.starts_with(&y.path)) {
+            ret.push(x);
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Checks the captures against the filters of `entry`, the same way `glob`
+/// does going forward.
+fn captures_allowed(entry: &SrcFile, captures: &BTreeMap<usize, String>) -> bool {
+    let mut filters: VecDeque<Match> = match filter_queue(entry) {
+        Ok(filters) => filters,
+        Err(_) => return false,
+    };
+    let mut index = 0;
+
+    for component in Path::new(&entry.from).components() {
+        if let Component::Normal(part) = component {
+            let mut part = capture_pattern(&part.to_string_lossy()).to_owned();
+            if let Some(filter) = brace_filter(&part) {
+                filters.push_front(filter);
+                part = "*".to_owned();
+            }
+
+            if part == "**" {
+                index += 1;
+                let filter = filters.pop_front().unwrap_or_default();
+                if let Some(capture) = captures.get(&index) {
+                    if !passes_recursive_filter(capture, &filter) {
+                        return false;
+                    }
+                }
+            } else if is_wildcard(&part) {
+                let re = match component_regex(&part, entry.is_case_sensitive()) {
+                    Ok(re) => re,
+                    Err(_) => return false,
+                };
+
+                index += 1;
+                if let Some(name) = captures.get(&index) {
+                    if !passes_filters(&re, name, &filters) {
+                        return false;
+                    }
+                }
+
+                for _ in 1..re.captures_len() {
+                    filters.pop_front();
+                }
+            }
+        }
+    }
+
+    true
+}
+
+/// Rebuilds the source path of `found`, a path under `root`, from its
+/// captures.
+///
+/// When `to` does not use every capture, or transforms them, the source is
+/// found by globbing instead (see `find_source`).
+pub(crate) fn source_from_captures(
+    entry: &SrcFile,
+    root: &Path,
+    found: &BackupMatch,
+    cipher: Option<&Cipher>,
+) -> Result<PathBuf, Error> {
+    let mut path = PathBuf::new();
+    let mut index = 0;
+
+    for component in Path::new(&entry.from).components() {
+        match component {
+            Component::Normal(part) => {
+                let part = part.to_string_lossy();
+                if is_capture(&part) {
+                    index += 1;
+                    match found.captures.get(&index) {
+                        Some(capture) if capture.is_empty() => {}
+                        Some(capture) => path.push(capture),
+                        None => return find_source(entry, root, found, cipher),
+                    }
+                } else {
+                    path.push(&*part);
+                }
+            }
+            component => path.push(component.as_os_str()),
+        }
+    }
+
+    Ok(path)
+}
+
+/// Whether `to` maps a source with the captures `matches` to `names`, the
+/// components of a backed up path. Components with a date match any value,
+/// since the backup may have been made on another day.
+fn maps_to(to: &[Template], matches: &[String], names: &[String]) -> bool {
+    let known: BTreeMap<usize, String> = matches
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (i + 1, x.clone()))
+        .collect();
+    let now = Local::now();
+    let mut rest = names;
+
+    for part in to {
+        if part.is_dated() {
+            match (part.regex(&known), rest.split_first()) {
+                (Ok((re, _)), Some((name, tail))) if re.is_match(name) => rest = tail,
+                _ => return false,
+            }
+            continue;
+        }
+
+        let expanded = match part.expand(matches, &now) {
+            Ok(expanded) => expanded,
+            Err(_) => return false,
+        };
+        for component in expanded.components() {
+            let component = component.as_os_str().to_string_lossy();
+            match rest.split_first() {
+                Some((name, tail)) if name.to_lowercase() == component.to_lowercase() => {
+                    rest = tail
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    rest.is_empty()
+}
+
+/// Finds the source of `found` by globbing `from`, and mapping each match
+/// through `to` to see which one was backed up there.
+fn find_source(
+    entry: &SrcFile,
+    root: &Path,
+    found: &BackupMatch,
+    cipher: Option<&Cipher>,
+) -> Result<PathBuf, Error> {
+    let to = template::parse_path(&entry.to, &capture_names(&entry.from))?;
+    let names = found
+        .path
+        .strip_prefix(root)
+        .unwrap_or(&found.path)
+        .components()
+        .map(|x| source_name(&x.as_os_str().to_string_lossy(), cipher))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            format_err!(
+                "unable to read the name of {}",
+                found.path.to_string_lossy()
+            )
+        })?;
+
+    let mut sources: Vec<PathBuf> = glob_entry(entry)?
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| maps_to(&to, &x.matches, &names))
+        .map(|x| x.path)
+        .collect();
+
+    match sources.len() {
+        1 => Ok(sources.pop().unwrap()),
+        _ => Err(format_err!(
+            "unable to determine source path, captures unused by to: {}",
+            entry.to
+        )),
+    }
+}
+
+/// Moves an absolute path under `root`, keeping the drive letter as a directory.
+fn reroot(root: &Path, path: &Path) -> PathBuf {
+    let mut ret = root.to_owned();
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                    ret.push((letter as char).to_string())
+                }
+                _ => {}
+            },
+            Component::Normal(part) => ret.push(part),
+            _ => {}
+        }
+    }
+
+    ret
+}
+
+/// The backup tree `restore` reads from when none is given: the first
+/// available destination.
+pub(crate) fn default_backup_tree(settings: &Settings) -> Result<PathBuf, Error> {
+    let (dest, root) = destinations(settings.dest.as_slice())
+        .into_iter()
+        .find_map(|(dest, root)| root.ok().map(|root| (dest, root)))
+        .ok_or_else(|| format_err!("no destination is available"))?;
+
+    if dest.archive != Archive::Off {
+        // Archives are named after the time they were written.
+        let mut archives: Vec<PathBuf> = fs::read_dir(&root)
+            .map(|entries| {
+                entries
+                    .filter_map(|x| x.ok())
+                    .map(|x| x.path())
+                    .filter(|x| {
+                        x.is_file() && !atomic::is_partial(x) && Archive::of(x) == dest.archive
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        archives.sort();
+
+        archives
+            .pop()
+            .ok_or_else(|| format_err!("no archives found in {}", root.to_string_lossy()))
+    } else if dest.repository {
+        repository::latest(&root).ok_or_else(|| {
+            format_err!(
+                "no repository snapshots found in {}",
+                root.to_string_lossy()
+            )
+        })
+    } else if settings.snapshots.enabled {
+        match snapshot::list(&root).pop() {
+            Some((_, path)) => Ok(path),
+            None => Err(format_err!(
+                "no snapshots found in {}",
+                root.to_string_lossy()
+            )),
+        }
+    } else {
+        Ok(root)
+    }
+}
+
+fn plan_restore(
+    settings: &Settings,
+    from: PathBuf,
+    options: RestoreOptions,
+    observer: &mut dyn BackupObserver,
+) -> Result<BackupPlan, Error> {
+    if !from.is_dir() {
+        return Err(format_err!("backup not found: {}", from.to_string_lossy()));
+    }
+
+    let cipher = Cipher::find(&from, &settings.encryption)?.map(Arc::new);
+    let crypt = match cipher {
+        Some(_) => Crypt::Decrypt,
+        None => Crypt::Off,
+    };
+
+    let mut run = Run {
+        observer,
+        comparator: Comparator::load(&from, Compare::Mtime),
+        journal: None,
+        names: Names::restore(cipher),
+        jobs: settings.config.jobs,
+        snapshot: None,
+        force: !options.keep_newer,
+        ignore: Ignore::default(),
+        base: PathBuf::new(),
+        files: vec![],
+    };
+
+    for entry in &settings.files {
+        for found in unglob(&from, entry, run.names.cipher())? {
+            run.observer.glob_expanded(entry, &found.path);
+
+            match source_from_captures(entry, &from, &found, run.names.cipher()) {
+                Ok(target) => {
+                    let target = match options.root {
+                        Some(ref root) => reroot(root, &target),
+                        None => target,
+                    };
+
+                    if let Err(e) = plan_copy(found.path.clone(), target.clone(), entry, &mut run) {
+                        run.record(PlannedFile::failed(found.path, Some(target), entry, e));
+                    }
+                }
+                Err(e) => run.record(PlannedFile::failed(found.path, None, entry, e)),
+            }
+        }
+    }
+
+    Ok(BackupPlan {
+        compare: Compare::Mtime,
+        jobs: settings.config.jobs,
+        preserve: settings.config.preserve.clone(),
+        crypt,
+        warnings: vec![],
+        destinations: vec![DestinationPlan {
+            label: from.to_string_lossy().into_owned(),
+            root: Some(from),
+            error: None,
+            files: run.files,
+            prune: vec![],
+            snapshot: None,
+            journal: false,
+            archive: None,
+            repository: None,
+            manifest: false,
+            encryption: None,
+        }],
+    })
+}
+
+/// Copies backed up files back to where they came from.
+pub fn restore(
+    settings: Settings,
+    options: RestoreOptions,
+    observer: &mut dyn BackupObserver,
+) -> Result<BackupReport, Error> {
+    let from = match options.from {
+        Some(ref from) => from.clone(),
+        None => default_backup_tree(&settings)?,
+    };
+
+    // Archives and repository snapshots are extracted, and restored from
+    // like a tree.
+    if from.is_file() {
+        let extracted = archive::Extracted::new(&from)?;
+        extracted.warn_failed(observer);
+        let mut plan = plan_restore(&settings, extracted.path.clone(), options, observer)?;
+        plan.destinations[0].label = from.to_string_lossy().into_owned();
+        return plan::execute(plan, &settings.encryption, observer, settings.config.dryrun);
+    }
+
+    let plan = plan_restore(&settings, from, options, observer)?;
+    plan::execute(plan, &settings.encryption, observer, settings.config.dryrun)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    /// Backs up the `docs` of each user under `src` to `users/<name>`.
+    fn backed_up(test: &str) -> (TempDir, Settings) {
+        let dir = TempDir::new(test);
+        dir.write("src/alice/docs/a.txt", "alice");
+        dir.write("src/bob/docs/b.txt", "bob");
+        dir.write("src/bob/music/c.txt", "music");
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}/*/docs\n    to: users/$1\n",
+                dir.join("src").display()
+            ),
+        );
+
+        testing::backup(settings.clone());
+        assert_eq!(dir.read("out/users/bob/b.txt").as_deref(), Some("bob"));
+        (dir, settings)
+    }
+
+    #[test]
+    fn restores_where_files_came_from() {
+        let (dir, settings) = backed_up("restore");
+        fs::remove_dir_all(dir.join("src")).unwrap();
+
+        let report = restore(settings, RestoreOptions::default(), &mut ()).unwrap();
+        assert_eq!((report.copies, report.errors), (2, 0));
+        assert_eq!(dir.read("src/alice/docs/a.txt").as_deref(), Some("alice"));
+        assert_eq!(dir.read("src/bob/docs/b.txt").as_deref(), Some("bob"));
+        assert!(!dir.join("src/bob/music").exists());
+    }
+
+    #[test]
+    fn restores_under_another_root() {
+        let (dir, settings) = backed_up("restore-root");
+        let options = RestoreOptions {
+            root: Some(dir.join("restored")),
+            ..RestoreOptions::default()
+        };
+
+        restore(settings, options, &mut ()).unwrap();
+        let restored = reroot(&dir.join("restored"), &dir.join("src/alice/docs/a.txt"));
+        assert_eq!(fs::read_to_string(restored).unwrap(), "alice");
+    }
+
+    #[test]
+    fn newer_files_can_be_kept() {
+        let (dir, settings) = backed_up("restore-keep-newer");
+        dir.write("src/alice/docs/a.txt", "edited");
+        let options = RestoreOptions {
+            keep_newer: true,
+            ..RestoreOptions::default()
+        };
+
+        restore(settings.clone(), options, &mut ()).unwrap();
+        assert_eq!(dir.read("src/alice/docs/a.txt").as_deref(), Some("edited"));
+
+        restore(settings, RestoreOptions::default(), &mut ()).unwrap();
+        assert_eq!(dir.read("src/alice/docs/a.txt").as_deref(), Some("alice"));
+    }
+    #[test]
+    fn transformed_captures_are_found_by_globbing() {
+        let dir = TempDir::new("restore-transformed");
+        dir.write("src/alice/docs/a.txt", "alice");
+        dir.write("src/bob/docs/b.txt", "bob");
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}/*/docs\n    to: users/${{1|upper}}\n",
+                dir.join("src").display()
+            ),
+        );
+        testing::backup(settings.clone());
+        assert_eq!(dir.read("out/users/ALICE/a.txt").as_deref(), Some("alice"));
+        fs::remove_file(dir.join("src/alice/docs/a.txt")).unwrap();
+
+        let report = restore(settings, RestoreOptions::default(), &mut ()).unwrap();
+        assert_eq!(report.errors, 0);
+        assert_eq!(dir.read("src/alice/docs/a.txt").as_deref(), Some("alice"));
+    }
+}
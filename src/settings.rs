@@ -1,18 +1,140 @@
 use config::{Config, Environment, File, FileFormat};
 use failure::Error;
 use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// What a destination is for when `dest` lists several.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Required: everything is copied to every mirror.
+    #[default]
+    Mirror,
+    /// The first available fallback is used.
+    Fallback,
+}
+
+impl Role {
+    fn is_mirror(&self) -> bool {
+        *self == Role::Mirror
+    }
+}
+
+/// How a destination stores what is backed up.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Archive {
+    /// A tree of files.
+    #[default]
+    Off,
+    /// A tar archive compressed with zstd, one per run.
+    TarZst,
+    /// A zip archive, one per run.
+    Zip,
+}
+
+impl Archive {
+    fn is_off(&self) -> bool {
+        *self == Archive::Off
+    }
+
+    /// The extension of archives in this format.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Archive::Off => None,
+            Archive::TarZst => Some("tar.zst"),
+            Archive::Zip => Some("zip"),
+        }
+    }
+
+    /// The format of the archive at `path`, from its extension.
+    pub fn of(path: &Path) -> Archive {
+        let name = path.to_string_lossy();
+        [Archive::TarZst, Archive::Zip]
+            .iter()
+            .cloned()
+            .find(|x| name.ends_with(&format!(".{}", x.extension().unwrap())))
+            .unwrap_or_default()
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn default_label() -> String {
+    "$CURRENTDRIVE".to_owned()
+}
+
+fn default_format() -> String {
+    "$HOSTNAME/".to_owned()
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DestDrive {
+    /// `$CURRENTDRIVE`, or a `label:`, `uuid:`, `mountpoint:`, `device:` or
+    /// `marker:` selector. `marker:NAME` picks the one drive that has a file
+    /// called NAME in its root. Without a prefix, a device or label.
+    #[serde(default = "default_label")]
     pub label: String,
+    #[serde(default = "default_format")]
     pub format: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Role::is_mirror")]
+    pub role: Role,
+    /// Write each run to a single archive under the destination instead of
+    /// a tree of files. Snapshots and mirroring don't apply to archives.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Archive::is_off")]
+    pub archive: Archive,
+    /// Store files as deduplicated chunks in a repository under the
+    /// destination, with a snapshot index per run. Snapshots and mirroring
+    /// don't apply to repositories, and indexes and chunks are never removed.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub repository: bool,
+}
+
+impl DestDrive {
+    /// Whether files are backed up as a tree of files, rather than to an
+    /// archive or repository.
+    pub fn is_tree(&self) -> bool {
+        self.archive.is_off() && !self.repository
+    }
 }
 
 impl Default for DestDrive {
     fn default() -> Self {
         DestDrive {
-            label: "$CURRENTDRIVE".to_owned(),
-            format: "$HOSTNAME/".to_owned(),
+            label: default_label(),
+            format: default_format(),
+            role: Role::default(),
+            archive: Archive::default(),
+            repository: false,
+        }
+    }
+}
+
+/// `dest` is either a single destination or a list of them.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Destinations {
+    One(DestDrive),
+    Many(Vec<DestDrive>),
+}
+
+impl Default for Destinations {
+    fn default() -> Self {
+        Destinations::One(DestDrive::default())
+    }
+}
+
+impl Destinations {
+    pub fn as_slice(&self) -> &[DestDrive] {
+        match self {
+            Destinations::One(dest) => std::slice::from_ref(dest),
+            Destinations::Many(dests) => dests,
         }
     }
 }
@@ -27,7 +149,7 @@ where
         .collect())
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct Match {
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty", deserialize_with = "lower_all")]
@@ -37,22 +159,165 @@ pub struct Match {
     pub only: Vec<String>,
 }
 
-impl Default for Match {
+/// Filters for the captures of `from`, either one per capture group in order,
+/// or by the name of a `{name:pattern}` capture.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Filters {
+    Named(BTreeMap<String, Match>),
+    Positional(Vec<Match>),
+}
+
+impl Default for Filters {
     fn default() -> Self {
-        Match {
-            exclude: vec![],
-            only: vec![],
+        Filters::Positional(vec![])
+    }
+}
+
+impl Filters {
+    fn is_empty(&self) -> bool {
+        match self {
+            Filters::Named(filters) => filters.is_empty(),
+            Filters::Positional(filters) => filters.is_empty(),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mirror {
+    #[default]
+    Off,
+    Delete,
+    Trash,
+}
+
+impl Mirror {
+    fn is_off(&self) -> bool {
+        *self == Mirror::Off
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct SrcFile {
     pub from: String,
     pub to: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Filters::is_empty")]
+    pub filters: Filters,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Mirror::is_off")]
+    pub mirror: Mirror,
     #[serde(default = "Vec::new")]
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    pub filters: Vec<Match>,
+    pub ignore: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case_sensitive: Option<bool>,
+}
+
+impl SrcFile {
+    /// Whether `from` and the ignore patterns match case-sensitively:
+    /// `case_sensitive` if set, otherwise the convention of the host's
+    /// filesystems.
+    pub fn is_case_sensitive(&self) -> bool {
+        self.case_sensitive
+            .unwrap_or(!cfg!(any(windows, target_os = "macos")))
+    }
+
+    /// Checks that every template in `to` parses, and that every named filter
+    /// refers to a capture in `from`. Nothing is looked up on the filesystem.
+    ///
+    /// A `from` that is not an absolute path on this host, like the Windows
+    /// defaults elsewhere, is split on `\` as well as `/`, and left to fail
+    /// when globbed.
+    pub fn validate(&self) -> Result<(), Error> {
+        if !Path::new(&self.from).is_absolute() {
+            return SrcFile {
+                from: self.from.replace('\\', "/"),
+                ..self.clone()
+            }
+            .validate_components();
+        }
+
+        self.validate_components()
+    }
+
+    fn validate_components(&self) -> Result<(), Error> {
+        let names = crate::capture_names(&self.from);
+
+        crate::template::parse_path(&self.to, &names)
+            .map_err(|e| format_err!("to field is invalid: {}: {}", self.to, e))?;
+
+        if let Filters::Named(ref filters) = self.filters {
+            for name in filters.keys() {
+                // Keys are lowercased when the config is loaded.
+                if !names.iter().flatten().any(|x| x.eq_ignore_ascii_case(name)) {
+                    return Err(format_err!(
+                        "filter references an unknown capture: {}: {}",
+                        self.from,
+                        name
+                    ));
+                }
+            }
+        }
+
+        crate::filter_queue(self).map(|_| ())
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compare {
+    #[default]
+    Mtime,
+    MtimeSize,
+    Blake3,
+    Sha256,
+}
+
+/// What to do when a destination lacks the space a backup needs.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpaceCheck {
+    /// Skip the destination without writing anything.
+    #[default]
+    Abort,
+    Warn,
+    Off,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The metadata copied along with each file.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Preserve {
+    /// Modification and access times.
+    #[serde(default = "default_true")]
+    pub times: bool,
+    /// Permission bits. Unix only: the read-only flag, all Windows has, would
+    /// stop later runs from replacing or removing the copy.
+    #[serde(default = "default_true")]
+    pub permissions: bool,
+    /// Owner and group, when the user running the backup may set them.
+    #[serde(default = "default_true")]
+    pub ownership: bool,
+    /// Extended attributes, which include ACLs on Linux. Unix only.
+    #[serde(default)]
+    pub xattrs: bool,
+}
+
+impl Default for Preserve {
+    fn default() -> Self {
+        Preserve {
+            times: true,
+            permissions: true,
+            ownership: true,
+            xattrs: false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -61,6 +326,21 @@ pub struct AppConfig {
     pub quiet: bool,
     #[serde(default)]
     pub dryrun: bool,
+    #[serde(default)]
+    pub compare: Compare,
+    #[serde(default = "Vec::new")]
+    pub ignore: Vec<String>,
+    #[serde(default)]
+    pub space_check: SpaceCheck,
+    /// Files compared and copied at once. 0 uses one thread per CPU.
+    #[serde(default = "default_jobs")]
+    pub jobs: usize,
+    #[serde(default)]
+    pub preserve: Preserve,
+}
+
+fn default_jobs() -> usize {
+    1
 }
 
 impl Default for AppConfig {
@@ -68,20 +348,85 @@ impl Default for AppConfig {
         AppConfig {
             quiet: false,
             dryrun: true,
+            compare: Compare::default(),
+            ignore: vec!["Thumbs.db".to_owned(), "~$*".to_owned()],
+            space_check: SpaceCheck::default(),
+            jobs: default_jobs(),
+            preserve: Preserve::default(),
         }
     }
 }
 
+fn default_keep_daily() -> u32 {
+    7
+}
+
+fn default_keep_weekly() -> u32 {
+    4
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Snapshots {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_keep_daily")]
+    pub keep_daily: u32,
+    #[serde(default = "default_keep_weekly")]
+    pub keep_weekly: u32,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Snapshots {
+            enabled: false,
+            keep_daily: default_keep_daily(),
+            keep_weekly: default_keep_weekly(),
+        }
+    }
+}
+
+/// Client-side encryption of backed up files.
+///
+/// The key is derived from `passphrase`, or from the `UBACKUP_PASSPHRASE`
+/// environment variable, unless `key_file` is set, in which case its contents
+/// are the key.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct Encryption {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// Also encrypt the names of backed up files and directories. Only takes
+    /// effect on a destination encrypted for the first time.
+    #[serde(default)]
+    pub filenames: bool,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Settings {
     #[serde(default)]
     pub config: AppConfig,
     #[serde(default)]
-    pub dest: DestDrive,
+    pub dest: Destinations,
+    #[serde(default)]
+    pub snapshots: Snapshots,
+    #[serde(default)]
+    pub encryption: Encryption,
     #[serde(default)]
     pub files: Vec<SrcFile>,
 }
 
+fn named(filters: Vec<(&str, Match)>) -> Filters {
+    Filters::Named(
+        filters
+            .into_iter()
+            .map(|(name, filter)| (name.to_owned(), filter))
+            .collect(),
+    )
+}
+
 impl Default for Settings {
     fn default() -> Self {
         let no_public_user = Match {
@@ -97,65 +442,80 @@ impl Default for Settings {
 
         Settings {
             config: AppConfig::default(),
-            dest: DestDrive::default(),
+            dest: Destinations::default(),
+            snapshots: Snapshots::default(),
+            encryption: Encryption::default(),
             files: vec![
                 SrcFile {
-                    from: "C:\\Users\\*\\*\\".to_owned(),
-                    to: "$1/$2/".to_owned(),
-                    filters: vec![
-                        no_public_user.clone(),
-                        Match {
-                            exclude: Vec::new(),
-                            only: vec![
-                                "Desktop".to_owned(),
-                                "Downloads".to_owned(),
-                                "Contacts".to_owned(),
-                            ],
-                        },
-                    ],
+                    from: "C:\\Users\\{user:*}\\{folder:*}\\".to_owned(),
+                    to: "${user}/${folder}/".to_owned(),
+                    filters: named(vec![
+                        ("user", no_public_user.clone()),
+                        (
+                            "folder",
+                            Match {
+                                exclude: vec![],
+                                only: vec![
+                                    "Desktop".to_owned(),
+                                    "Downloads".to_owned(),
+                                    "Contacts".to_owned(),
+                                ],
+                            },
+                        ),
+                    ]),
+                    ..Default::default()
                 },
                 SrcFile {
-                    from: "C:\\Users\\*\\Documents\\*\\".to_owned(),
-                    to: "$1/Documents/$2/".to_owned(),
-                    filters: vec![no_public_user.clone(),
-                    Match {
-                        exclude: vec![
-                            "My Music".to_owned(),
-                            "My Pictures".to_owned(),
-                            "My Videos".to_owned(),
-                        ],
-                        only: vec![],
-                    }],
+                    from: "C:\\Users\\{user:*}\\Documents\\{folder:*}\\".to_owned(),
+                    to: "${user}/Documents/${folder}/".to_owned(),
+                    filters: named(vec![
+                        ("user", no_public_user.clone()),
+                        (
+                            "folder",
+                            Match {
+                                exclude: vec![
+                                    "My Music".to_owned(),
+                                    "My Pictures".to_owned(),
+                                    "My Videos".to_owned(),
+                                ],
+                                only: vec![],
+                            },
+                        ),
+                    ]),
+                    ..Default::default()
                 },
                 SrcFile {
-                    from: "C:\\Users\\*\\Favorites".to_owned(),
-                    to: "$1/Favorites/IE/".to_owned(),
-                    filters: vec![no_public_user.clone()],
+                    from: "C:\\Users\\{user:*}\\Favorites".to_owned(),
+                    to: "${user}/Favorites/IE/".to_owned(),
+                    filters: named(vec![("user", no_public_user.clone())]),
+                    ..Default::default()
                 },
                 SrcFile {
-                    from: "C:\\Users\\*\\AppData\\Local\\Packages\\Microsoft.MicrosoftEdge_*\\AC\\MicrosoftEdge\\User\\*\\Favorites".to_owned(),
-                    to: "$1/Favorites/Edge/$3".to_owned(),
-                    filters: vec![no_public_user.clone()],
+                    from: "C:\\Users\\{user:*}\\AppData\\Local\\Packages\\Microsoft.MicrosoftEdge_*\\AC\\MicrosoftEdge\\User\\{profile:*}\\Favorites".to_owned(),
+                    to: "${user}/Favorites/Edge/${profile}".to_owned(),
+                    filters: named(vec![("user", no_public_user.clone())]),
+                    ..Default::default()
                 },
                 SrcFile {
-                    from: "C:\\Users\\*\\AppData\\Local\\Google\\Chrome\\User Data\\*\\Bookmarks".to_owned(),
-                    to: "$1/Favorites/Chrome/$2/Bookmarks".to_owned(),
-                    filters: vec![no_public_user.clone()],
+                    from: "C:\\Users\\{user:*}\\AppData\\Local\\Google\\Chrome\\User Data\\{profile:*}\\Bookmarks".to_owned(),
+                    to: "${user}/Favorites/Chrome/${profile}/Bookmarks".to_owned(),
+                    filters: named(vec![("user", no_public_user.clone())]),
+                    ..Default::default()
                 },
                 SrcFile {
-                    from: "C:\\Users\\*\\AppData\\Roaming\\Mozilla\\Firefox\\Profiles\\*\\*.sqlite".to_owned(),
-                    to: "$1/Favorites/Firefox/$2/$3".to_owned(),
-                    filters: vec![
-                        no_public_user,
-                        Match::default(),
-                        Match {
-                            exclude: vec![],
-                            only: vec![
-                                "places".to_owned(),
-                                "favicons".to_owned(),
-                            ]
-                        }
-                    ],
+                    from: "C:\\Users\\{user:*}\\AppData\\Roaming\\Mozilla\\Firefox\\Profiles\\{profile:*}\\{db:*.sqlite}".to_owned(),
+                    to: "${user}/Favorites/Firefox/${profile}/${db}".to_owned(),
+                    filters: named(vec![
+                        ("user", no_public_user),
+                        (
+                            "db",
+                            Match {
+                                exclude: vec![],
+                                only: vec!["places".to_owned(), "favicons".to_owned()],
+                            },
+                        ),
+                    ]),
+                    ..Default::default()
                 },
             ],
         }
@@ -171,15 +531,38 @@ impl Settings {
             FileFormat::Yaml,
         ))?;
 
-        match file {
-            Some(file) => {
-                s.merge(File::with_name(file))?;
-            }
-            _ => {}
+        if let Some(file) = file {
+            s.merge(File::with_name(file))?;
         }
 
         s.merge(Environment::with_prefix("ubackup"))?;
 
-        Ok(s.try_into()?)
+        let settings: Settings = s.try_into()?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    /// Checks every entry in `files`, see `SrcFile::validate`.
+    pub fn validate(&self) -> Result<(), Error> {
+        for entry in &self.files {
+            entry.validate()?;
+        }
+
+        for dest in self.dest.as_slice() {
+            if dest.repository && !dest.archive.is_off() {
+                return Err(format_err!(
+                    "a destination can't be both an archive and a repository"
+                ));
+            }
+        }
+
+        if self.encryption.enabled && self.dest.as_slice().iter().any(|x| !x.is_tree()) {
+            return Err(format_err!(
+                "encryption is not supported with archive or repository destinations"
+            ));
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,159 @@
+use crate::settings::Snapshots;
+use chrono::{Datelike, Local, NaiveDateTime};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub(crate) const SNAPSHOT_FORMAT: &str = "%Y-%m-%dT%H%M%S";
+
+/// Lists the snapshot directories under `root`, oldest first.
+pub(crate) fn list(root: &Path) -> Vec<(NaiveDateTime, PathBuf)> {
+    let mut snapshots: Vec<(NaiveDateTime, PathBuf)> = match fs::read_dir(root) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                NaiveDateTime::parse_from_str(&name, SNAPSHOT_FORMAT)
+                    .ok()
+                    .map(|date| (date, entry.path()))
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+
+    snapshots.sort();
+    snapshots
+}
+
+/// Returns the directory for a new snapshot, or `resume` if an interrupted run
+/// left one, and the latest other existing snapshot.
+pub(crate) fn begin(root: &Path, resume: Option<PathBuf>) -> (PathBuf, Option<PathBuf>) {
+    let current =
+        resume.unwrap_or_else(|| root.join(Local::now().format(SNAPSHOT_FORMAT).to_string()));
+    let previous = list(root)
+        .into_iter()
+        .map(|(_, path)| path)
+        .rfind(|path| path != &current);
+
+    (current, previous)
+}
+
+/// The snapshots not kept by the retention policy once `current` is added.
+///
+/// The newest snapshot of each of the last `keep_daily` days and of each of
+/// the last `keep_weekly` ISO weeks is kept, as is the newest snapshot overall.
+pub(crate) fn expired(root: &Path, settings: &Snapshots, current: &Path) -> Vec<PathBuf> {
+    let mut snapshots = list(root);
+
+    if let Some(name) = current.file_name() {
+        if let Ok(date) = NaiveDateTime::parse_from_str(&name.to_string_lossy(), SNAPSHOT_FORMAT) {
+            if !snapshots.iter().any(|(_, path)| path == current) {
+                snapshots.push((date, current.to_owned()));
+                snapshots.sort();
+            }
+        }
+    }
+
+    let mut keep: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut days = BTreeSet::new();
+    let mut weeks = BTreeSet::new();
+
+    for (date, path) in snapshots.iter().rev() {
+        if keep.is_empty() {
+            keep.insert(path.clone());
+        }
+
+        if days.len() < settings.keep_daily as usize && days.insert(date.date()) {
+            keep.insert(path.clone());
+        }
+
+        let week = date.iso_week();
+        if weeks.len() < settings.keep_weekly as usize && weeks.insert((week.year(), week.week())) {
+            keep.insert(path.clone());
+        }
+    }
+
+    snapshots
+        .into_iter()
+        .map(|(_, path)| path)
+        .filter(|path| !keep.contains(path) && path != current)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const SNAPSHOTS: [&str; 5] = [
+        "2024-03-01T100000",
+        "2024-03-04T100000",
+        "2024-03-09T090000",
+        "2024-03-09T140000",
+        "2024-03-10T120000",
+    ];
+
+    fn snapshots(test: &str) -> TempDir {
+        let dir = TempDir::new(test);
+        for name in SNAPSHOTS {
+            dir.write(&format!("{}/file", name), name);
+        }
+        dir.write("other/file", "");
+        dir.write("2024-03-11T100000", "not a snapshot");
+        dir
+    }
+
+    fn keep(keep_daily: u32, keep_weekly: u32) -> Snapshots {
+        Snapshots {
+            enabled: true,
+            keep_daily,
+            keep_weekly,
+        }
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|x| x.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn lists_snapshot_directories() {
+        let dir = snapshots("snapshot-list");
+
+        let listed: Vec<_> = list(&dir.path).into_iter().map(|(_, x)| x).collect();
+        assert_eq!(names(&listed), SNAPSHOTS);
+
+        let (current, previous) = begin(&dir.path, None);
+        assert_eq!(previous, Some(dir.join("2024-03-10T120000")));
+        assert_eq!(current.parent(), Some(dir.path.as_path()));
+
+        // An interrupted run carries on in its snapshot.
+        let resumed = dir.join("2024-03-10T120000");
+        let (current, previous) = begin(&dir.path, Some(resumed.clone()));
+        assert_eq!(current, resumed);
+        assert_eq!(previous, Some(dir.join("2024-03-09T140000")));
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_of_each_day_and_week() {
+        let dir = snapshots("snapshot-prune");
+
+        // The 10th and 9th for the days, and the 1st for the week before.
+        let current = dir.join("2024-03-10T120000");
+        let expired = expired(&dir.path, &keep(2, 2), &current);
+        assert_eq!(names(&expired), ["2024-03-04T100000", "2024-03-09T090000"]);
+    }
+
+    #[test]
+    fn retention_counts_the_current_snapshot() {
+        let dir = snapshots("snapshot-expired-current");
+
+        let current = dir.join("2024-03-11T080000");
+        let expired = expired(&dir.path, &keep(1, 1), &current);
+        assert_eq!(names(&expired), SNAPSHOTS);
+        assert!(dir.join("2024-03-01T100000").exists());
+    }
+}
@@ -0,0 +1,490 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use failure::Error;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Key {
+    Capture(usize),
+    Env(String),
+    Date(String),
+    Hostname,
+    User,
+    Os,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Transform {
+    Lower,
+    Upper,
+    Replace(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Var {
+        key: Key,
+        transforms: Vec<Transform>,
+    },
+}
+
+/// One component of `dest.format` or `to`.
+///
+/// `$NAME` and `${NAME|transform|...}` expand to a capture of `from` (by
+/// index or name), `env:VAR`, `DATE`, `HOSTNAME`, `USER` or `OS`.
+/// `$DATE{format}` takes a chrono format string, and `$$` is a literal `$`.
+/// The transforms are `lower`, `upper` and `replace:from,to`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Template {
+    pieces: Vec<Piece>,
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn parse_key(key: &str, names: &[Option<String>]) -> Result<Key, Error> {
+    if let Some(var) = key.strip_prefix("env:") {
+        return Ok(Key::Env(var.to_owned()));
+    }
+
+    if let Ok(i) = key.parse::<usize>() {
+        return if i >= 1 && i <= names.len() {
+            Ok(Key::Capture(i))
+        } else {
+            Err(format_err!("unknown capture: ${}", key))
+        };
+    }
+
+    if let Some(i) = names.iter().position(|x| x.as_deref() == Some(key)) {
+        return Ok(Key::Capture(i + 1));
+    }
+
+    match key {
+        "DATE" => Ok(Key::Date(DEFAULT_DATE_FORMAT.to_owned())),
+        "HOSTNAME" => Ok(Key::Hostname),
+        "USER" => Ok(Key::User),
+        "OS" => Ok(Key::Os),
+        _ => Err(format_err!("unknown capture or variable: ${}", key)),
+    }
+}
+
+/// Checks that `format` is a valid chrono format string, since formatting a
+/// date with one that isn't panics.
+fn parse_date(format: String) -> Result<Key, Error> {
+    if StrftimeItems::new(&format).any(|x| matches!(x, Item::Error)) {
+        return Err(format_err!("invalid date format: {}", format));
+    }
+    Ok(Key::Date(format))
+}
+
+fn parse_transform(transform: &str) -> Result<Transform, Error> {
+    if let Some(args) = transform.strip_prefix("replace:") {
+        return match args.find(',') {
+            Some(i) => Ok(Transform::Replace(
+                args[..i].to_owned(),
+                args[i + 1..].to_owned(),
+            )),
+            None => Err(format_err!("replace needs two arguments: {}", transform)),
+        };
+    }
+
+    match transform {
+        "lower" => Ok(Transform::Lower),
+        "upper" => Ok(Transform::Upper),
+        _ => Err(format_err!("unknown transform: {}", transform)),
+    }
+}
+
+impl Template {
+    /// Parses `template`, resolving captures against the names `capture_names`
+    /// returns for `from`.
+    pub fn parse(template: &str, names: &[Option<String>]) -> Result<Template, Error> {
+        let mut pieces = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        let invalid = |e: Error| format_err!("template is invalid: {}: {}", template, e);
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                text.push(c);
+                continue;
+            }
+
+            let var = match chars.peek() {
+                Some('$') => {
+                    chars.next();
+                    text.push('$');
+                    continue;
+                }
+                Some('{') => {
+                    chars.next();
+                    let expr: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    let mut parts = expr.split('|');
+                    let key =
+                        parse_key(parts.next().unwrap_or_default(), names).map_err(invalid)?;
+                    let transforms = parts
+                        .map(parse_transform)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(invalid)?;
+
+                    Piece::Var { key, transforms }
+                }
+                _ => {
+                    let mut name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if !is_ident(c) {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+
+                    let key = if name == "DATE" && chars.peek() == Some(&'{') {
+                        chars.next();
+                        parse_date(chars.by_ref().take_while(|&c| c != '}').collect())
+                            .map_err(invalid)?
+                    } else {
+                        parse_key(&name, names).map_err(invalid)?
+                    };
+
+                    Piece::Var {
+                        key,
+                        transforms: vec![],
+                    }
+                }
+            };
+
+            if !text.is_empty() {
+                pieces.push(Piece::Text(text.split_off(0)));
+            }
+            pieces.push(var);
+        }
+
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+
+        Ok(Template { pieces })
+    }
+
+    /// Expands the template with `captures`, `$1` being `captures[0]`.
+    ///
+    /// The expansion stays one component, or several when a `**` capture
+    /// spans directories: values with separators, `.` or `..` are rejected.
+    pub fn expand(&self, captures: &[String], now: &DateTime<Local>) -> Result<PathBuf, Error> {
+        let mut ret = String::new();
+
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => ret.push_str(text),
+                Piece::Var { key, transforms } => {
+                    let mut value = match key {
+                        Key::Capture(i) => match captures.get(i - 1) {
+                            Some(capture) => capture.clone(),
+                            None => return Err(format_err!("unknown capture: ${}", i)),
+                        },
+                        Key::Date(format) => now.format(format).to_string(),
+                        key => static_value(key),
+                    };
+
+                    for transform in transforms {
+                        value = match transform {
+                            Transform::Lower => value.to_lowercase(),
+                            Transform::Upper => value.to_uppercase(),
+                            Transform::Replace(from, to) => value.replace(from.as_str(), to),
+                        };
+                    }
+
+                    // Only `**` captures hold `/`, between the directories
+                    // they matched, since names can't contain it.
+                    let spans_dirs = matches!(key, Key::Capture(_)) && transforms.is_empty();
+                    if value.contains('\\') || (!spans_dirs && value.contains('/')) {
+                        return Err(format_err!("expanded to a path: {}", value));
+                    }
+
+                    ret.push_str(&value);
+                }
+            }
+        }
+
+        let mut path = PathBuf::new();
+        if ret.is_empty() {
+            return Ok(path);
+        }
+
+        for part in ret.split('/') {
+            if part.is_empty() || part == "." || part == ".." {
+                return Err(format_err!("expanded to an invalid name: {}", ret));
+            }
+            path.push(part);
+        }
+
+        Ok(path)
+    }
+
+    /// The capture index if the template is exactly one untransformed capture.
+    pub fn capture(&self) -> Option<usize> {
+        match self.pieces.as_slice() {
+            [Piece::Var {
+                key: Key::Capture(i),
+                transforms,
+            }] if transforms.is_empty() => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Whether the template has a `$DATE`, so that it expands differently
+    /// from one day to the next.
+    pub fn is_dated(&self) -> bool {
+        self.pieces.iter().any(|piece| {
+            matches!(
+                piece,
+                Piece::Var {
+                    key: Key::Date(_),
+                    ..
+                }
+            )
+        })
+    }
+
+    /// The text of the template if it contains no variables.
+    pub fn literal(&self) -> Option<String> {
+        self.pieces
+            .iter()
+            .try_fold(String::new(), |mut ret, piece| match piece {
+                Piece::Text(text) => {
+                    ret.push_str(text);
+                    Some(ret)
+                }
+                _ => None,
+            })
+    }
+
+    /// A regex matching what the template could have expanded to, and the
+    /// capture index of each of its groups.
+    ///
+    /// Untransformed captures not in `known` become groups. Dates and
+    /// transformed values match anything, since they cannot be inverted.
+    pub fn regex(&self, known: &BTreeMap<usize, String>) -> Result<(Regex, Vec<usize>), Error> {
+        let mut re = String::from("(?i)^");
+        let mut groups = vec![];
+
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => re.push_str(&regex::escape(text)),
+                Piece::Var { key, transforms } if transforms.is_empty() => match key {
+                    Key::Capture(i) => match known.get(i) {
+                        Some(capture) => re.push_str(&regex::escape(capture)),
+                        None => {
+                            re.push_str("(.*?)");
+                            groups.push(*i);
+                        }
+                    },
+                    Key::Date(_) => re.push_str(".*?"),
+                    key => re.push_str(&regex::escape(&static_value(key))),
+                },
+                Piece::Var { .. } => re.push_str(".*?"),
+            }
+        }
+
+        re.push('$');
+        Ok((Regex::new(&re)?, groups))
+    }
+}
+
+fn static_value(key: &Key) -> String {
+    match key {
+        Key::Env(var) => env::var(var).unwrap_or_default(),
+        Key::Hostname => hostname::get_hostname().unwrap_or_default(),
+        Key::User => env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .unwrap_or_default(),
+        Key::Os => env::consts::OS.to_owned(),
+        Key::Capture(_) | Key::Date(_) => String::new(),
+    }
+}
+
+/// Parses each component of `path` as a template.
+pub(crate) fn parse_path(path: &str, names: &[Option<String>]) -> Result<Vec<Template>, Error> {
+    let mut parts = vec![];
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::RootDir => {}
+            Component::Normal(part) => parts.push(Template::parse(&part.to_string_lossy(), names)?),
+            _ => return Err(format_err!("path is invalid: {}", path)),
+        }
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 3, 9, 14, 5, 0).unwrap()
+    }
+
+    fn names() -> Vec<Option<String>> {
+        vec![Some("user".to_owned()), None, Some("dirs".to_owned())]
+    }
+
+    fn expand(template: &str, captures: &[&str]) -> Result<PathBuf, Error> {
+        let captures: Vec<String> = captures.iter().map(|x| x.to_string()).collect();
+        Template::parse(template, &names())?.expand(&captures, &now())
+    }
+
+    #[test]
+    fn captures_by_index_and_name() {
+        let captures = ["alice", "Music", "a/b"];
+
+        assert_eq!(
+            expand("$1-$2", &captures).unwrap(),
+            Path::new("alice-Music")
+        );
+        assert_eq!(
+            expand("${user}_x", &captures).unwrap(),
+            Path::new("alice_x")
+        );
+        assert_eq!(
+            expand("$user.bak", &captures).unwrap(),
+            Path::new("alice.bak")
+        );
+        assert_eq!(expand("$$1", &captures).unwrap(), Path::new("$1"));
+    }
+
+    #[test]
+    fn transforms() {
+        let captures = ["Alice Smith", "", ""];
+
+        assert_eq!(
+            expand("${user|lower}", &captures).unwrap(),
+            Path::new("alice smith")
+        );
+        assert_eq!(
+            expand("${1|upper|replace: ,_}", &captures).unwrap(),
+            Path::new("ALICE_SMITH")
+        );
+    }
+
+    #[test]
+    fn variables() {
+        env::set_var("UBACKUP_TEMPLATE_TEST", "value");
+
+        assert_eq!(expand("$DATE", &[]).unwrap(), Path::new("2024-03-09"));
+        assert_eq!(expand("$DATE{%Y%m}", &[]).unwrap(), Path::new("202403"));
+        assert_eq!(
+            expand("${DATE|upper}", &[]).unwrap(),
+            Path::new("2024-03-09")
+        );
+        assert_eq!(
+            expand("${env:UBACKUP_TEMPLATE_TEST}", &[]).unwrap(),
+            Path::new("value")
+        );
+        assert_eq!(expand("$OS", &[]).unwrap(), Path::new(env::consts::OS));
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in [
+            "$4",
+            "$0",
+            "${nobody}",
+            "${user|title}",
+            "${user|replace:a}",
+            "$UNKNOWN",
+            "$DATE{%Q}",
+            "$DATE{%Y-%}",
+        ] {
+            assert!(Template::parse(template, &names()).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn recursive_captures_span_directories() {
+        assert_eq!(
+            expand("${dirs}", &["", "", "a/b/c"]).unwrap(),
+            Path::new("a").join("b").join("c")
+        );
+        assert_eq!(expand("${dirs}", &["", "", ""]).unwrap(), PathBuf::new());
+    }
+
+    #[test]
+    fn paths_are_rejected() {
+        env::set_var("UBACKUP_TEMPLATE_PATH", "../escape");
+
+        for (template, captures) in [
+            ("${env:UBACKUP_TEMPLATE_PATH}", ["", "", ""]),
+            ("$DATE{%Y/%m}", ["", "", ""]),
+            ("${user|replace:a,/}", ["a", "", ""]),
+            ("$1", ["..", "", ""]),
+            ("$1", [".", "", ""]),
+            (".$1", [".", "", ""]),
+            ("$1", ["a\\b", "", ""]),
+            ("${dirs}", ["", "", "a/../b"]),
+            ("${dirs}", ["", "", "/etc"]),
+        ] {
+            assert!(
+                expand(template, &captures).is_err(),
+                "{} {:?}",
+                template,
+                captures
+            );
+        }
+    }
+
+    #[test]
+    fn captures_and_literals() {
+        let names = names();
+
+        assert_eq!(Template::parse("$1", &names).unwrap().capture(), Some(1));
+        assert_eq!(
+            Template::parse("${user}", &names).unwrap().capture(),
+            Some(1)
+        );
+        assert_eq!(
+            Template::parse("${user|lower}", &names).unwrap().capture(),
+            None
+        );
+        assert_eq!(Template::parse("x$1", &names).unwrap().capture(), None);
+
+        assert_eq!(
+            Template::parse("a$$b", &names).unwrap().literal(),
+            Some("a$b".to_owned())
+        );
+        assert_eq!(Template::parse("a$1", &names).unwrap().literal(), None);
+    }
+
+    #[test]
+    fn regexes() {
+        let template = Template::parse("${user}-$2-$DATE", &names()).unwrap();
+
+        let (re, groups) = template.regex(&BTreeMap::new()).unwrap();
+        assert_eq!(groups, vec![1, 2]);
+        let cap = re.captures("alice-music-2024-03-09").unwrap();
+        assert_eq!(&cap[1], "alice");
+
+        let known = vec![(1, "alice".to_owned())].into_iter().collect();
+        let (re, groups) = template.regex(&known).unwrap();
+        assert_eq!(groups, vec![2]);
+        assert!(re.is_match("ALICE-music-2024"));
+        assert!(!re.is_match("bob-music-2024"));
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(parse_path("/a/$1/b", &names()).unwrap().len(), 3);
+        assert!(parse_path("a/../b", &names()).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{BackupReport, Settings};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A new directory under the system's temporary directory, removed when
+/// dropped.
+pub(crate) struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(test: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "ubackup-{}-{}-{}",
+            test,
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn join(&self, path: &str) -> PathBuf {
+        self.path.join(path)
+    }
+
+    /// Writes `contents` to `path` under the directory, creating its parents.
+    pub fn write(&self, path: &str, contents: &str) -> PathBuf {
+        let path = self.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// The contents of `path` under the directory, if it is a file.
+    pub fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(self.join(path)).ok()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// `dest` as a `dest.format` on the current drive.
+pub(crate) fn format(dest: &Path) -> String {
+    let format: PathBuf = dest
+        .components()
+        .filter(|x| matches!(x, Component::Normal(_)))
+        .collect();
+    format.to_string_lossy().into_owned()
+}
+
+/// Settings that back up to `dest` on the current drive for real, with the
+/// rest of the settings read from `yaml`.
+pub(crate) fn settings(dest: &Path, yaml: &str) -> Settings {
+    let yaml = format!(
+        "config:\n  dryrun: false\ndest:\n  label: $CURRENTDRIVE\n  format: {}\n{}",
+        format(dest),
+        yaml
+    );
+    serde_yaml::from_str(&yaml).unwrap()
+}
+
+pub(crate) fn backup(settings: Settings) -> BackupReport {
+    crate::backup(settings, &mut ()).unwrap()
+}
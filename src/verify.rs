@@ -0,0 +1,309 @@
+use crate::archive;
+use crate::compare::{self, Comparator, Hasher};
+use crate::crypto::{self, Cipher, Names};
+use crate::ignore::Ignore;
+use crate::observer::BackupObserver;
+use crate::plan::{Action, PlannedFile};
+use crate::pool;
+use crate::restore::default_backup_tree;
+use crate::settings::{Compare, Settings};
+use crate::{plan_entry, Run, RESERVED_PREFIX};
+use chrono::Local;
+use failure::Error;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Default, Clone)]
+pub struct VerifyOptions {
+    /// Backup tree, archive or repository snapshot index to verify. Defaults
+    /// to where `restore` reads from.
+    pub from: Option<PathBuf>,
+}
+
+/// How a backed up file differs from its source.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum Mismatch {
+    /// A source has no backed up copy.
+    Missing,
+    Differs {
+        reason: String,
+    },
+    /// A backed up file no source maps to.
+    Extra,
+    /// The source or its copy could not be read.
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct VerifiedFile {
+    #[serde(flatten)]
+    pub mismatch: Mismatch,
+    pub src: Option<PathBuf>,
+    pub dest: PathBuf,
+}
+
+/// What `verify` found. Files that match are only counted.
+#[derive(Debug, Serialize, Clone)]
+pub struct VerifyReport {
+    pub from: PathBuf,
+    pub matches: u32,
+    pub missing: u32,
+    pub differing: u32,
+    pub extra: u32,
+    pub errors: u32,
+    pub files: Vec<VerifiedFile>,
+}
+
+impl VerifyReport {
+    fn push(&mut self, file: VerifiedFile) {
+        match file.mismatch {
+            Mismatch::Missing => self.missing += 1,
+            Mismatch::Differs { .. } => self.differing += 1,
+            Mismatch::Extra => self.extra += 1,
+            Mismatch::Failed { .. } => self.errors += 1,
+        }
+        self.files.push(file);
+    }
+
+    /// Whether every source matches its copy, with nothing else backed up.
+    pub fn is_ok(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// The BLAKE3 hash of the contents of `dest`, decrypted with `cipher`.
+fn dest_hash(dest: &Path, cipher: Option<&Cipher>) -> Result<String, Error> {
+    match cipher {
+        Some(cipher) => {
+            let mut hasher = Hasher::new(Compare::Blake3);
+            cipher.decrypt(BufReader::new(File::open(dest)?), &mut hasher)?;
+            Ok(hasher.finish())
+        }
+        None => compare::hash_file(dest, Compare::Blake3),
+    }
+}
+
+/// Compares the size and contents of `src` with its copy at `dest`.
+fn check(src: &Path, dest: &Path, cipher: Option<&Cipher>) -> Result<Option<Mismatch>, Error> {
+    let size = src.metadata()?.len();
+    let dest_size = match dest.metadata() {
+        Ok(md) if md.is_file() => md.len(),
+        _ => return Ok(Some(Mismatch::Missing)),
+    };
+
+    let expected = match cipher {
+        Some(_) => crypto::encrypted_len(size),
+        None => size,
+    };
+    if dest_size != expected {
+        return Ok(Some(Mismatch::Differs {
+            reason: "size".to_owned(),
+        }));
+    }
+
+    if compare::hash_file(src, Compare::Blake3)? != dest_hash(dest, cipher)? {
+        return Ok(Some(Mismatch::Differs {
+            reason: "contents".to_owned(),
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Lists the backed up files under `dir`, leaving out ubackup's own files.
+fn backed_up_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|x| x.ok()) {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(RESERVED_PREFIX)
+        {
+            continue;
+        }
+
+        let path = entry.path();
+        if path.is_dir() {
+            backed_up_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Maps every source in `settings.files` to its copy in the tree at `tree`
+/// and compares them, then looks for files in the tree no source maps to.
+/// Paths are reported under `from`.
+fn verify_tree(
+    settings: &Settings,
+    tree: &Path,
+    from: &Path,
+    observer: &mut dyn BackupObserver,
+) -> Result<VerifyReport, Error> {
+    let cipher = Cipher::find(tree, &settings.encryption)?.map(Arc::new);
+    let now = Local::now();
+
+    let mut run = Run {
+        observer,
+        comparator: Comparator::load(tree, Compare::Mtime),
+        journal: None,
+        names: Names::backup(cipher.clone()),
+        jobs: settings.config.jobs,
+        snapshot: None,
+        force: true,
+        ignore: Ignore::default(),
+        base: PathBuf::new(),
+        files: vec![],
+    };
+    for entry in &settings.files {
+        plan_entry(settings, entry, tree, &now, &mut run)?;
+    }
+
+    let shown = |dest: &Path| match dest.strip_prefix(tree) {
+        Ok(rel) => from.join(rel),
+        Err(_) => dest.to_owned(),
+    };
+
+    let mut report = VerifyReport {
+        from: from.to_owned(),
+        matches: 0,
+        missing: 0,
+        differing: 0,
+        extra: 0,
+        errors: 0,
+        files: vec![],
+    };
+    let mut expected = HashSet::new();
+
+    pool::for_each_ordered(
+        &run.files,
+        settings.config.jobs,
+        |file: &PlannedFile| match (&file.action, &file.dest) {
+            (Action::Fail { error }, _) => Err(format_err!("{}", error)),
+            (_, Some(dest)) => check(&file.src, dest, cipher.as_deref()),
+            (_, None) => Err(format_err!("no destination planned")),
+        },
+        |file, result| {
+            if let Some(ref dest) = file.dest {
+                expected.insert(dest.clone());
+            }
+
+            let mismatch = match result {
+                Ok(None) => {
+                    report.matches += 1;
+                    return;
+                }
+                Ok(Some(mismatch)) => mismatch,
+                Err(e) => Mismatch::Failed {
+                    error: e.to_string(),
+                },
+            };
+            report.push(VerifiedFile {
+                mismatch,
+                src: Some(file.src.clone()),
+                dest: shown(file.dest.as_deref().unwrap_or(tree)),
+            });
+        },
+    );
+
+    let mut found = vec![];
+    backed_up_files(tree, &mut found);
+    found.sort();
+    for dest in found {
+        if !expected.contains(&dest) {
+            report.push(VerifiedFile {
+                mismatch: Mismatch::Extra,
+                src: None,
+                dest: shown(&dest),
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+/// Checks that the backup matches the sources: every source has a copy of
+/// the same size and contents, and nothing else is backed up.
+pub fn verify(
+    settings: &Settings,
+    options: VerifyOptions,
+    observer: &mut dyn BackupObserver,
+) -> Result<VerifyReport, Error> {
+    let from = match options.from {
+        Some(from) => from,
+        None => default_backup_tree(settings)?,
+    };
+
+    // Archives and repository snapshots are extracted, and verified like a
+    // tree.
+    if from.is_file() {
+        let extracted = archive::Extracted::new(&from)?;
+        extracted.warn_failed(observer);
+        return verify_tree(settings, &extracted.path, &from, observer);
+    }
+
+    if !from.is_dir() {
+        return Err(format_err!("backup not found: {}", from.to_string_lossy()));
+    }
+    verify_tree(settings, &from, &from, observer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn backed_up(test: &str) -> (TempDir, Settings) {
+        let dir = TempDir::new(test);
+        dir.write("src/a", "first");
+        dir.write("src/b", "second");
+        dir.write("src/c", "third");
+        let settings = testing::settings(
+            &dir.join("out"),
+            &format!(
+                "files:\n  - from: {}\n    to: copy\n",
+                dir.join("src").display()
+            ),
+        );
+        testing::backup(settings.clone());
+        (dir, settings)
+    }
+
+    #[test]
+    fn backups_match_their_sources() {
+        let (_dir, settings) = backed_up("verify-ok");
+        let report = verify(&settings, VerifyOptions::default(), &mut ()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.matches, 3);
+    }
+
+    #[test]
+    fn mismatches_are_reported() {
+        let (dir, settings) = backed_up("verify-mismatches");
+        dir.write("out/copy/a", "changed contents");
+        fs::remove_file(dir.join("out/copy/b")).unwrap();
+        dir.write("out/copy/d", "extra");
+
+        let report = verify(&settings, VerifyOptions::default(), &mut ()).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(
+            (
+                report.matches,
+                report.differing,
+                report.missing,
+                report.extra
+            ),
+            (1, 1, 1, 1)
+        );
+    }
+}
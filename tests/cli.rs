@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A directory for `test` with a config backing up `src` to `out`, the rest
+/// of the config being `yaml`.
+fn setup(test: &str, yaml: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ubackup-cli-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("src/a"), "a").unwrap();
+
+    let format: PathBuf = dir
+        .join("out")
+        .components()
+        .filter(|x| matches!(x, Component::Normal(_)))
+        .collect();
+    fs::write(
+        dir.join("config.yaml"),
+        format!(
+            "config:\n  dryrun: false\ndest:\n  label: $CURRENTDRIVE\n  format: {}\nfiles:\n  - from: {}\n    to: copy\n{}",
+            format.to_string_lossy(),
+            dir.join("src").to_string_lossy(),
+            yaml
+        ),
+    )
+    .unwrap();
+    dir
+}
+
+fn ubackup(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ubackup"))
+        .arg("-c")
+        .arg(dir.join("config.yaml"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn json_lines() {
+    let dir = setup("json", "");
+    let output = ubackup(&dir, &["-f", "json"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+    assert!(events
+        .iter()
+        .any(|x| x["event"] == "file" && x["outcome"] == "copied"));
+    let summary = events.last().unwrap();
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["copies"], 1);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn exit_codes() {
+    let dir = setup("exit-codes", "");
+    assert_eq!(ubackup(&dir, &["-q"]).status.code(), Some(0));
+
+    // A file in the way of the directory backed up to.
+    fs::remove_dir_all(dir.join("out")).unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+    fs::write(dir.join("out/copy"), "").unwrap();
+    assert_eq!(ubackup(&dir, &["-q"]).status.code(), Some(1));
+
+    fs::write(dir.join("config.yaml"), "files: {").unwrap();
+    assert_eq!(ubackup(&dir, &["-q"]).status.code(), Some(2));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn arguments() {
+    let dir = setup("arguments", "");
+    for args in [&["--help"][..], &["--version"], &["verify", "--help"]] {
+        let output = ubackup(&dir, args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", args);
+        assert!(!output.stdout.is_empty());
+    }
+
+    for args in [&["--bogus"][..], &["-f", "xml"], &["restore", "--from"]] {
+        assert_eq!(ubackup(&dir, args).status.code(), Some(2), "{:?}", args);
+    }
+
+    // `-q` goes after the subcommand too, like `-d` and `-r`.
+    assert_eq!(ubackup(&dir, &["-q"]).status.code(), Some(0));
+    let output = ubackup(&dir, &["verify", "-q"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    fs::remove_dir_all(dir).unwrap();
+}